@@ -1,11 +1,22 @@
-use std::hash::Hasher;
 use std::{
-    collections::hash_map::DefaultHasher,
     env, fs,
     path::{Path, PathBuf},
+    time::SystemTime,
 };
 use std::{error::Error, process::Command};
 
+use ring::digest::{digest, SHA256};
+use walkdir::WalkDir;
+
+// oldest toolchains the frontend and backend are known to build with
+const MIN_NODE: u32 = 18;
+const MIN_PNPM: u32 = 8;
+const MIN_JAVA: u32 = 17;
+
+// point these at already built artifacts to skip building the submodules
+const PREBUILT_DIST: &str = "YOUTUBE_SERVER_DIST";
+const PREBUILT_JAR: &str = "YOUTUBE_SERVER_JAR";
+
 fn main() -> Result<(), Box<dyn Error>> {
     let windows = std::env::var("CARGO_CFG_TARGET_OS")? == "windows";
     let host_windows = cfg!(target_os = "windows");
@@ -18,88 +29,255 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
 
-    let piped_dir = manifest_dir.join("piped");
-    let node_dir = piped_dir.join("node_modules");
-    let dist_dir = piped_dir.join("dist");
+    // without these the files are loaded at runtime instead, so node and java aren't needed
+    let embed_frontend = env::var_os("CARGO_FEATURE_EMBED_FRONTEND").is_some();
+    let embed_backend = env::var_os("CARGO_FEATURE_EMBED_BACKEND").is_some();
 
     println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed={PREBUILT_DIST}");
+    println!("cargo:rerun-if-env-changed={PREBUILT_JAR}");
 
-    // re-run in case these get deleted or whatever
-    println!("cargo:rerun-if-changed={}", node_dir.display());
-    println!("cargo:rerun-if-changed={}", dist_dir.display());
+    if embed_frontend {
+        let dist_dir = frontend(&manifest_dir.join("piped"), host_windows)?;
+        println!("cargo:rustc-env=PIPED_DIST_DIR={}", dist_dir.display());
+    }
 
-    let piped_backend_dir = manifest_dir.join("piped-backend");
-    let build_dir = piped_backend_dir.join("build");
+    if embed_backend {
+        let piped_jar = backend(&manifest_dir.join("piped-backend"), host_windows)?;
+        println!("cargo:rustc-env=PIPED_JAR_PATH={}", piped_jar.display());
 
-    println!("cargo:rerun-if-changed={}", piped_backend_dir.display());
+        // hash the jar, and make a rust file containing it
+        let out_dir = env::var("OUT_DIR").unwrap();
+        let out_dir = Path::new(&out_dir);
 
-    let pnpm_cmd = if windows { "pnpm.cmd" } else { "pnpm" };
+        // sha256, so it stays the same across rust versions and can verify the extracted jar
+        let piped_jar = fs::read(piped_jar)?;
+        let hash = digest(&SHA256, &piped_jar)
+            .as_ref()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<String>();
 
-    // install dependencies
+        let out_file = out_dir.join("hash.rs");
+        fs::write(
+            out_file,
+            format!("mod hash {{ pub const JAR_HASH: &str = \"{hash}\"; }}"),
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Builds the frontend if its sources changed, and returns the dist dir to embed
+fn frontend(piped_dir: &Path, host_windows: bool) -> Result<PathBuf, Box<dyn Error>> {
+    if let Some(dist_dir) = env::var_os(PREBUILT_DIST).map(PathBuf::from) {
+        if !dist_dir.join("index.html").is_file() {
+            return Err(format!(
+                "{PREBUILT_DIST} is set to {}, which has no index.html",
+                dist_dir.display()
+            )
+            .into());
+        }
+
+        println!("cargo:rerun-if-changed={}", dist_dir.display());
+        return Ok(dist_dir);
+    }
+
+    let node_dir = piped_dir.join("node_modules");
+    let dist_dir = piped_dir.join("dist");
+
+    if !piped_dir.join("package.json").exists() {
+        return Err(format!(
+            "the frontend sources are missing. Run `git submodule update --init`, set {PREBUILT_DIST} \
+             to a built dist, or build without the embed-frontend feature"
+        )
+        .into());
+    }
+
+    let ignore = ["node_modules", "dist", ".git"];
+    watch(piped_dir, &ignore)?;
+
+    // re-run in case these get deleted or whatever
+    println!(
+        "cargo:rerun-if-changed={}",
+        dist_dir.join("index.html").display()
+    );
+
+    let built = modified(&dist_dir.join("index.html"));
+    if built.is_some() && built >= newest(piped_dir, &ignore) {
+        return Ok(dist_dir);
+    }
+
+    let hint =
+        format!("set {PREBUILT_DIST} to a built dist, or build without the embed-frontend feature");
+    check_version("node", &["--version"], MIN_NODE, &hint)?;
+    let pnpm_cmd = if host_windows { "pnpm.cmd" } else { "pnpm" };
+    check_version(pnpm_cmd, &["--version"], MIN_PNPM, &hint)?;
 
     // install dependencies
     // pnpm install
-    if !node_dir.exists() {
-        Command::new(pnpm_cmd)
-            .arg("install")
-            .current_dir(&piped_dir)
-            .spawn()
-            .unwrap()
-            .wait()
-            .unwrap();
+    let lockfile = modified(&piped_dir.join("pnpm-lock.yaml"));
+    if !node_dir.exists() || lockfile > modified(&node_dir) {
+        run(Command::new(pnpm_cmd).arg("install").current_dir(piped_dir))?;
     }
 
     // compile dist
     // pnpm build
-    if !dist_dir.exists() {
-        Command::new(pnpm_cmd)
-            .arg("build")
-            .current_dir(&piped_dir)
-            .spawn()
-            .unwrap()
-            .wait()
-            .unwrap();
+    run(Command::new(pnpm_cmd).arg("build").current_dir(piped_dir))?;
+
+    Ok(dist_dir)
+}
+
+/// Builds the backend if its sources changed, and returns the jar to embed
+fn backend(piped_backend_dir: &Path, host_windows: bool) -> Result<PathBuf, Box<dyn Error>> {
+    if let Some(piped_jar) = env::var_os(PREBUILT_JAR).map(PathBuf::from) {
+        if !piped_jar.is_file() {
+            return Err(format!(
+                "{PREBUILT_JAR} is set to {}, which doesn't exist",
+                piped_jar.display()
+            )
+            .into());
+        }
+
+        println!("cargo:rerun-if-changed={}", piped_jar.display());
+        return Ok(piped_jar);
+    }
+
+    let mut piped_jar = piped_backend_dir.join("build");
+    piped_jar.push("libs");
+    piped_jar.push("piped-1.0-all.jar");
+
+    if !piped_backend_dir.join("build.gradle").exists() {
+        return Err(format!(
+            "the backend sources are missing. Run `git submodule update --init`, set {PREBUILT_JAR} \
+             to a built jar, or build without the embed-backend feature"
+        )
+        .into());
     }
 
+    let ignore = ["build", ".gradle", ".git"];
+    watch(piped_backend_dir, &ignore)?;
+    println!("cargo:rerun-if-changed={}", piped_jar.display());
+
+    let built = modified(&piped_jar);
+    if built.is_some() && built >= newest(piped_backend_dir, &ignore) {
+        return Ok(piped_jar);
+    }
+
+    let hint =
+        format!("set {PREBUILT_JAR} to a built jar, or build without the embed-backend feature");
+    check_version("java", &["-version"], MIN_JAVA, &hint)?;
+
     // compile backend
-    if !build_dir.exists() {
-        let shell = if host_windows { "cmd" } else { "bash" };
-
-        let gradlew = if host_windows {
-            "gradlew.bat"
-        } else {
-            "gradlew"
-        };
-
-        Command::new(shell)
-            .args([
-                if host_windows { "/c" } else { "-c" },
-                &format!("{gradlew} shadowJar"),
-            ])
-            .current_dir(&piped_backend_dir)
-            .spawn()
-            .unwrap()
-            .wait()
-            .unwrap();
+    let shell = if host_windows { "cmd" } else { "bash" };
+
+    let gradlew = if host_windows {
+        "gradlew.bat"
+    } else {
+        "gradlew"
+    };
+
+    run(Command::new(shell)
+        .args([
+            if host_windows { "/c" } else { "-c" },
+            &format!("{gradlew} shadowJar"),
+        ])
+        .current_dir(piped_backend_dir))?;
+
+    if !piped_jar.exists() {
+        return Err(format!("gradle succeeded, but {} is missing", piped_jar.display()).into());
     }
 
-    // get path to piped jar, hash it, and make a rust file containing it
-    let out_dir = env::var("OUT_DIR").unwrap();
-    let out_dir = Path::new(&out_dir);
-    let mut build_piped_jar = build_dir.join("libs");
-    build_piped_jar.push("piped-1.0-all.jar");
+    Ok(piped_jar)
+}
 
-    let mut hasher = DefaultHasher::new();
-    let piped_jar = fs::read(build_piped_jar).unwrap();
-    hasher.write(&piped_jar);
-    let hash = hasher.finish();
+/// Runs a build command, failing the build if it does
+fn run(command: &mut Command) -> Result<(), Box<dyn Error>> {
+    let name = format!("{command:?}");
 
-    let out_file = out_dir.join("hash.rs");
-    fs::write(
-        out_file,
-        format!("mod hash {{ pub const JAR_HASH: u64 = {hash}; }}"),
-    )
-    .unwrap();
+    let status = command
+        .status()
+        .map_err(|e| format!("failed to run {name}: {e}"))?;
+
+    if !status.success() {
+        return Err(format!("{name} failed with {status}").into());
+    }
 
     Ok(())
 }
+
+/// Makes sure `program` is installed and at least version `min`
+fn check_version(program: &str, args: &[&str], min: u32, hint: &str) -> Result<(), Box<dyn Error>> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|_| format!("{program} was not found on PATH. Install it, or {hint}"))?;
+
+    // java prints its version to stderr
+    let text = format!(
+        "{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let major = major_version(&text).ok_or(format!(
+        "couldn't read the version of {program} from {text:?}"
+    ))?;
+
+    if major < min {
+        return Err(format!(
+            "{program} {major} is too old, at least {min} is needed. Update it, or {hint}"
+        )
+        .into());
+    }
+
+    println!(
+        "cargo:warning=using {program} {}",
+        text.lines().next().unwrap_or_default().trim()
+    );
+
+    Ok(())
+}
+
+// eg: `v18.17.1`, `8.10.0`, `openjdk version "17.0.8" 2023-07-18`, `java version "1.8.0_381"`
+fn major_version(text: &str) -> Option<u32> {
+    let start = text.find(|c: char| c.is_ascii_digit())?;
+    let version = text[start..]
+        .split(|c: char| !c.is_ascii_digit() && c != '.')
+        .next()?;
+
+    let mut parts = version.split('.').filter_map(|p| p.parse::<u32>().ok());
+    match parts.next()? {
+        // old java versions are 1.x
+        1 => parts.next(),
+        major => Some(major),
+    }
+}
+
+/// Re-runs the build script when anything in `dir` changes, other than the `ignore`d entries
+fn watch(dir: &Path, ignore: &[&str]) -> Result<(), Box<dyn Error>> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+
+        if !ignore.iter().any(|i| entry.file_name() == *i) {
+            println!("cargo:rerun-if-changed={}", entry.path().display());
+        }
+    }
+
+    Ok(())
+}
+
+/// The newest modification time of any file in `dir`, other than the `ignore`d top level entries
+fn newest(dir: &Path, ignore: &[&str]) -> Option<SystemTime> {
+    WalkDir::new(dir)
+        .into_iter()
+        .filter_entry(|entry| entry.depth() != 1 || !ignore.iter().any(|i| entry.file_name() == *i))
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .filter_map(|entry| entry.metadata().ok()?.modified().ok())
+        .max()
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).ok()?.modified().ok()
+}
@@ -0,0 +1,594 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::PathBuf,
+    sync::{Arc, OnceLock, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail};
+use axum::{extract::Path, http::StatusCode, routing::get, Router};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as B64, Engine};
+use directories::ProjectDirs;
+use rcgen::{Certificate as RcgenCertificate, CertificateParams, CustomExtension};
+use reqwest::{header, Client};
+use ring::{
+    digest::{digest, SHA256},
+    rand::SystemRandom,
+    signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING},
+};
+use rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::{any_supported_type, CertifiedKey},
+    Certificate, PrivateKey, ServerConfig,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::time::sleep;
+use x509_parser::{extensions::GeneralName, parse_x509_certificate};
+
+use crate::{
+    config::{self, Challenge, Config},
+    files, resolver,
+    tasks::Tasks,
+};
+
+// https://www.rfc-editor.org/rfc/rfc8737#section-6.2
+const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+
+// renew when the certificate has less than this much time left
+const RENEW_BEFORE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+const RETRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+static STORE: OnceLock<Arc<CertStore>> = OnceLock::new();
+
+/// Holds the current certificate and any pending challenges. Since every listener resolves
+/// its certificate through here, renewed certificates are picked up without a restart
+#[derive(Default)]
+struct CertStore {
+    cert: RwLock<Option<Arc<CertifiedKey>>>,
+    // tls-alpn-01 challenge certificates by domain
+    alpn_challenges: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+    // http-01 key authorizations by token
+    http_challenges: RwLock<HashMap<String, String>>,
+}
+
+impl ResolvesServerCert for CertStore {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let is_challenge = client_hello
+            .alpn()
+            .is_some_and(|mut protocols| protocols.any(|p| p == ACME_TLS_ALPN));
+
+        if is_challenge {
+            let domain = client_hello.server_name()?;
+            return self.alpn_challenges.read().unwrap().get(domain).cloned();
+        }
+
+        self.cert.read().unwrap().clone()
+    }
+}
+
+/// Loads any stored certificate, then keeps it issued and renewed in the background
+pub fn start(config: Arc<Config>, tasks: &mut Tasks) -> anyhow::Result<()> {
+    let dir = acme_dir()?;
+    let store = Arc::new(CertStore::default());
+
+    if let (Ok(chain), Ok(key)) = (
+        fs::read(dir.join("cert.pem")),
+        fs::read(dir.join("key.pem")),
+    ) {
+        match certified_key(&chain, &key) {
+            Ok(certified) => *store.cert.write().unwrap() = Some(Arc::new(certified)),
+            // renewing replaces it
+            Err(e) => eprintln!("warning: ignoring the stored acme certificate: {e}"),
+        }
+    }
+
+    STORE
+        .set(store)
+        .map_err(|_| anyhow!("acme already started"))?;
+
+    if config.acme.challenge == Challenge::Http01 {
+        let challenge_addr = resolver::get_addresses(config.acme.http_challenge_address())?;
+        let challenge_addr = challenge_addr
+            .ipv4
+            .or(challenge_addr.ipv6)
+            .ok_or(anyhow!("Failed to resolve http challenge address"))?;
+
+        tasks.spawn("acme http challenge listener", move || async move {
+            let app =
+                Router::new().route("/.well-known/acme-challenge/:token", get(http_challenge));
+
+            axum::Server::try_bind(&challenge_addr)?
+                .serve(app.into_make_service())
+                .await?;
+
+            Ok(())
+        });
+    }
+
+    tasks.spawn("acme renewal", move || renew_loop(config.clone()));
+
+    Ok(())
+}
+
+async fn renew_loop(config: Arc<Config>) -> anyhow::Result<()> {
+    // give the listeners a moment to come up, tls-alpn-01 is answered on them
+    sleep(Duration::from_secs(5)).await;
+
+    loop {
+        let wait = match renew_if_needed(&config).await {
+            Ok(()) => CHECK_INTERVAL,
+            Err(e) => {
+                eprintln!("acme: failed to get certificate: {e:?}");
+                RETRY_INTERVAL
+            }
+        };
+
+        sleep(wait).await;
+    }
+}
+
+/// Server config for the ssl listeners, with certificates resolved from the acme store
+pub fn server_config() -> anyhow::Result<Arc<ServerConfig>> {
+    let store = STORE.get().ok_or(anyhow!("acme not started"))?.clone();
+
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(store);
+
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec(), ACME_TLS_ALPN.to_vec()];
+
+    Ok(Arc::new(config))
+}
+
+async fn http_challenge(Path(token): Path<String>) -> Result<String, StatusCode> {
+    let store = STORE.get().ok_or(StatusCode::NOT_FOUND)?;
+
+    store
+        .http_challenges
+        .read()
+        .unwrap()
+        .get(&token)
+        .cloned()
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+fn acme_dir() -> anyhow::Result<PathBuf> {
+    let project_dir = ProjectDirs::from("", "", "youtube-server")
+        .ok_or(anyhow!("Failed to get project directory"))?;
+
+    let dir = project_dir.data_local_dir().join("acme");
+    fs::create_dir_all(&dir)?;
+
+    Ok(dir)
+}
+
+fn domains(config: &Config) -> Vec<String> {
+    let mut domains = config
+        .addresses
+        .ssl_hosts()
+        .into_iter()
+        .map(str::to_string)
+        .chain(config.acme.domains.iter().flatten().cloned())
+        .collect::<Vec<_>>();
+
+    domains.sort_unstable();
+    domains.dedup();
+    domains
+}
+
+fn certified_key(chain_pem: &[u8], key_pem: &[u8]) -> anyhow::Result<CertifiedKey> {
+    let chain = rustls_pemfile::certs(&mut &*chain_pem)?
+        .into_iter()
+        .map(Certificate)
+        .collect::<Vec<_>>();
+
+    let key = rustls_pemfile::pkcs8_private_keys(&mut &*key_pem)?
+        .pop()
+        .ok_or(anyhow!("no private key found"))?;
+
+    // eg: a crash between writing a new key and its cert
+    let end_entity = chain.first().ok_or(anyhow!("no certificate found"))?;
+    let (_, end_entity) = parse_x509_certificate(&end_entity.0)?;
+    if end_entity.public_key().subject_public_key.data
+        != rcgen::KeyPair::from_der(&key)?.public_key_raw()
+    {
+        bail!("certificate doesn't match the private key");
+    }
+
+    Ok(CertifiedKey::new(
+        chain,
+        any_supported_type(&PrivateKey(key))?,
+    ))
+}
+
+/// Whether the current certificate is missing, about to expire, or doesn't cover every domain
+fn needs_renewal(store: &CertStore, domains: &[String]) -> bool {
+    let cert = store.cert.read().unwrap().clone();
+    let Some(cert) = cert else {
+        return true;
+    };
+
+    let Some(Ok((_, cert))) = cert
+        .end_entity_cert()
+        .ok()
+        .map(|c| parse_x509_certificate(&c.0))
+    else {
+        return true;
+    };
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    if cert.validity().not_after.timestamp() - now < RENEW_BEFORE.as_secs() as i64 {
+        return true;
+    }
+
+    let names = match cert.subject_alternative_name() {
+        Ok(Some(san)) => san
+            .value
+            .general_names
+            .iter()
+            .filter_map(|name| match name {
+                GeneralName::DNSName(name) => Some(*name),
+                _ => None,
+            })
+            .collect::<Vec<_>>(),
+        _ => return true,
+    };
+
+    !domains.iter().all(|d| names.contains(&d.as_str()))
+}
+
+async fn renew_if_needed(config: &Config) -> anyhow::Result<()> {
+    let store = STORE.get().unwrap();
+    let domains = domains(config);
+
+    if !needs_renewal(store, &domains) {
+        return Ok(());
+    }
+
+    println!("acme: requesting certificate for {}", domains.join(", "));
+
+    let (chain, key) = issue(config, store, &domains).await?;
+
+    let certified = certified_key(chain.as_bytes(), key.as_bytes())?;
+
+    // key first, so an interrupted write is caught by the pair not matching on the next start
+    let dir = acme_dir()?;
+    files::write_atomic(&dir.join("key.pem"), key.as_bytes(), Some(0o600))?;
+    files::write_atomic(&dir.join("cert.pem"), chain.as_bytes(), Some(0o600))?;
+
+    *store.cert.write().unwrap() = Some(Arc::new(certified));
+
+    println!("acme: certificate installed");
+
+    Ok(())
+}
+
+/// Runs a full order. Returns the pem certificate chain and its pem private key
+async fn issue(
+    config: &Config,
+    store: &CertStore,
+    domains: &[String],
+) -> anyhow::Result<(String, String)> {
+    let cert = RcgenCertificate::from_params(CertificateParams::new(domains.to_vec()))?;
+
+    let chain = order(config, store, domains, &cert).await;
+
+    // challenges are only valid for this order
+    store.alpn_challenges.write().unwrap().clear();
+    store.http_challenges.write().unwrap().clear();
+
+    Ok((chain?, cert.serialize_private_key_pem()))
+}
+
+async fn order(
+    config: &Config,
+    store: &CertStore,
+    domains: &[String],
+    cert: &RcgenCertificate,
+) -> anyhow::Result<String> {
+    let mut client = AcmeClient::new(config).await?;
+
+    let identifiers = domains
+        .iter()
+        .map(|d| json!({ "type": "dns", "value": d }))
+        .collect::<Vec<_>>();
+
+    let new_order = client.directory.new_order.clone();
+    let response = client
+        .post(&new_order, Some(json!({ "identifiers": identifiers })))
+        .await?;
+    let order_url = location(&response)?;
+    let order = response.json::<Value>().await?;
+
+    let authorizations = order["authorizations"]
+        .as_array()
+        .ok_or(anyhow!("order has no authorizations"))?;
+
+    for authorization in authorizations {
+        let url = authorization
+            .as_str()
+            .ok_or(anyhow!("invalid authorization"))?;
+
+        let authz = client.post(url, None).await?.json::<Value>().await?;
+        if authz["status"] == "valid" {
+            continue;
+        }
+
+        let domain = authz["identifier"]["value"]
+            .as_str()
+            .ok_or(anyhow!("authorization has no identifier"))?;
+
+        let kind = match config.acme.challenge {
+            Challenge::Http01 => "http-01",
+            Challenge::TlsAlpn01 => "tls-alpn-01",
+        };
+
+        let challenge = authz["challenges"]
+            .as_array()
+            .and_then(|c| c.iter().find(|c| c["type"] == kind))
+            .ok_or(anyhow!("no {kind} challenge offered for {domain}"))?;
+
+        let token = challenge["token"]
+            .as_str()
+            .ok_or(anyhow!("challenge has no token"))?;
+        let key_authorization = format!("{token}.{}", client.thumbprint());
+
+        match config.acme.challenge {
+            Challenge::Http01 => {
+                store
+                    .http_challenges
+                    .write()
+                    .unwrap()
+                    .insert(token.to_string(), key_authorization);
+            }
+
+            Challenge::TlsAlpn01 => {
+                let digest = digest(&SHA256, key_authorization.as_bytes());
+
+                let mut params = CertificateParams::new(vec![domain.to_string()]);
+                params.custom_extensions =
+                    vec![CustomExtension::new_acme_identifier(digest.as_ref())];
+                let challenge_cert = RcgenCertificate::from_params(params)?;
+
+                let key =
+                    any_supported_type(&PrivateKey(challenge_cert.serialize_private_key_der()))?;
+                let certified =
+                    CertifiedKey::new(vec![Certificate(challenge_cert.serialize_der()?)], key);
+
+                store
+                    .alpn_challenges
+                    .write()
+                    .unwrap()
+                    .insert(domain.to_string(), Arc::new(certified));
+            }
+        }
+
+        let challenge_url = challenge["url"]
+            .as_str()
+            .ok_or(anyhow!("challenge has no url"))?;
+        client.post(challenge_url, Some(json!({}))).await?;
+
+        client.poll(url, "valid").await?;
+    }
+
+    let finalize = order["finalize"]
+        .as_str()
+        .ok_or(anyhow!("order has no finalize url"))?;
+    let csr = B64.encode(cert.serialize_request_der()?);
+    client.post(finalize, Some(json!({ "csr": csr }))).await?;
+
+    let order = client.poll(&order_url, "valid").await?;
+    let certificate = order["certificate"]
+        .as_str()
+        .ok_or(anyhow!("order has no certificate"))?;
+
+    Ok(client.post(certificate, None).await?.text().await?)
+}
+
+fn location(response: &reqwest::Response) -> anyhow::Result<String> {
+    Ok(response
+        .headers()
+        .get(header::LOCATION)
+        .ok_or(anyhow!("response has no location"))?
+        .to_str()?
+        .to_string())
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+/// Minimal RFC 8555 client, just enough to get a certificate
+struct AcmeClient {
+    client: Client,
+    directory: Directory,
+    key: EcdsaKeyPair,
+    rng: SystemRandom,
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+impl AcmeClient {
+    async fn new(config: &Config) -> anyhow::Result<Self> {
+        let mut builder = Client::builder();
+        if let Some(ca_cert) = &config.acme.directory_ca_cert {
+            builder = builder.add_root_certificate(reqwest::Certificate::from_pem(&fs::read(
+                config::resolve_path(ca_cert)?,
+            )?)?);
+        }
+        let client = builder.build()?;
+
+        let directory = client
+            .get(config.acme.directory_url())
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Directory>()
+            .await?;
+
+        let rng = SystemRandom::new();
+
+        // the account is identified by its key, so keep it around
+        let key_path = acme_dir()?.join("account.key");
+        let pkcs8 = match fs::read(&key_path) {
+            Ok(pkcs8) => pkcs8,
+            Err(_) => {
+                let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+                    .map_err(|_| anyhow!("failed to generate account key"))?;
+                files::write_atomic(&key_path, pkcs8.as_ref(), Some(0o600))?;
+                pkcs8.as_ref().to_vec()
+            }
+        };
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8, &rng)
+            .map_err(|_| anyhow!("invalid account key"))?;
+
+        let mut acme = Self {
+            client,
+            directory,
+            key,
+            rng,
+            kid: None,
+            nonce: None,
+        };
+
+        // creates the account, or finds the existing one for this key
+        let new_account = acme.directory.new_account.clone();
+        let response = acme
+            .post(
+                &new_account,
+                Some(json!({
+                    "termsOfServiceAgreed": true,
+                    "contact": config.acme.contact,
+                })),
+            )
+            .await?;
+        acme.kid = Some(location(&response)?);
+
+        Ok(acme)
+    }
+
+    fn jwk(&self) -> (String, String) {
+        // uncompressed point, 0x04 || x || y
+        let public = self.key.public_key().as_ref();
+        (B64.encode(&public[1..33]), B64.encode(&public[33..65]))
+    }
+
+    // https://www.rfc-editor.org/rfc/rfc7638
+    fn thumbprint(&self) -> String {
+        let (x, y) = self.jwk();
+        let jwk = format!(r#"{{"crv":"P-256","kty":"EC","x":"{x}","y":"{y}"}}"#);
+        B64.encode(digest(&SHA256, jwk.as_bytes()))
+    }
+
+    async fn nonce(&mut self) -> anyhow::Result<String> {
+        if let Some(nonce) = self.nonce.take() {
+            return Ok(nonce);
+        }
+
+        let response = self.client.head(&self.directory.new_nonce).send().await?;
+        Ok(response
+            .headers()
+            .get("replay-nonce")
+            .ok_or(anyhow!("no nonce returned"))?
+            .to_str()?
+            .to_string())
+    }
+
+    /// Signed POST. A `None` payload is a POST-as-GET
+    async fn post(
+        &mut self,
+        url: &str,
+        payload: Option<Value>,
+    ) -> anyhow::Result<reqwest::Response> {
+        let mut retried = false;
+
+        loop {
+            let nonce = self.nonce().await?;
+
+            let protected = if let Some(kid) = &self.kid {
+                json!({ "alg": "ES256", "kid": kid, "nonce": nonce, "url": url })
+            } else {
+                let (x, y) = self.jwk();
+                json!({
+                    "alg": "ES256",
+                    "jwk": { "crv": "P-256", "kty": "EC", "x": x, "y": y },
+                    "nonce": nonce,
+                    "url": url,
+                })
+            };
+
+            let protected = B64.encode(protected.to_string());
+            let payload = payload
+                .as_ref()
+                .map(|p| B64.encode(p.to_string()))
+                .unwrap_or_default();
+            let signature = self
+                .key
+                .sign(&self.rng, format!("{protected}.{payload}").as_bytes())
+                .map_err(|_| anyhow!("failed to sign request"))?;
+
+            let body = json!({
+                "protected": protected,
+                "payload": payload,
+                "signature": B64.encode(signature),
+            });
+
+            let response = self
+                .client
+                .post(url)
+                .header(header::CONTENT_TYPE, "application/jose+json")
+                .body(body.to_string())
+                .send()
+                .await?;
+
+            self.nonce = response
+                .headers()
+                .get("replay-nonce")
+                .and_then(|n| n.to_str().ok())
+                .map(str::to_string);
+
+            if response.status().is_success() {
+                return Ok(response);
+            }
+
+            let status = response.status();
+            let problem = response.json::<Value>().await.unwrap_or_default();
+
+            // nonces can expire, the server hands out a fresh one with the error
+            if !retried && problem["type"] == "urn:ietf:params:acme:error:badNonce" {
+                retried = true;
+                continue;
+            }
+
+            bail!("{url} returned {status}: {problem}");
+        }
+    }
+
+    /// Polls an authorization or order until it reaches `status`
+    async fn poll(&mut self, url: &str, status: &str) -> anyhow::Result<Value> {
+        for _ in 0..30 {
+            let value = self.post(url, None).await?.json::<Value>().await?;
+
+            if value["status"] == status {
+                return Ok(value);
+            } else if value["status"] == "invalid" {
+                bail!("{url} became invalid: {value}");
+            }
+
+            sleep(Duration::from_secs(2)).await;
+        }
+
+        bail!("timed out waiting for {url} to become {status}")
+    }
+}
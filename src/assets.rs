@@ -1,45 +1,377 @@
-use std::{collections::HashMap, sync::OnceLock};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+    path::Path,
+    sync::OnceLock,
+};
 
 use aho_corasick::AhoCorasick;
+#[cfg(not(feature = "embed-frontend"))]
+use anyhow::anyhow;
+use base64::{engine::general_purpose::STANDARD as B64, Engine};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+#[cfg(feature = "embed-frontend")]
+use include_dir::Dir;
+use regex::Regex;
+use ring::digest::{digest, SHA256};
+use serde_json::json;
 
-use crate::config::Config;
+use crate::config::{resolve_path, Config, Rewrite};
+#[cfg(feature = "embed-frontend")]
 use crate::PIPED_SRC;
 
-static ASSETS: OnceLock<HashMap<String, String>> = OnceLock::new();
+// every file the frontend serves, by path relative to the dist root (eg: `assets/index.js`)
+static FILES: OnceLock<HashMap<String, &'static [u8]>> = OnceLock::new();
+static INLINE_SCRIPT_HASH: OnceLock<String> = OnceLock::new();
+// files which differ from the dist, because they were patched or overridden
+static MODIFIED: OnceLock<HashSet<String>> = OnceLock::new();
+
+// where the frontend fetches the public instance list from
+const INSTANCE_REGISTRY: &str = "https://piped-instances.kavin.rocks/";
+const INSTANCES_PATH: &str = "instances.json";
+// the frontend falls back to parsing this markdown list if the registry fails. Our list is served
+// right next to the frontend so that shouldn't happen, and if it does the public list mustn't
+// be fetched, so it's pointed at an empty file
+const INSTANCE_FALLBACK: &str =
+    "https://raw.githubusercontent.com/wiki/TeamPiped/Piped-Frontend/Instances.md";
+const INSTANCE_FALLBACK_PATH: &str = "instances.md";
+
+/// Loads the frontend files, from the embedded dist (or `dist_dir` if set, which it must be without
+/// the embed-frontend feature) with any files in `override_dir` on top, then patches the backend
+/// address and `rewrites` into every text file
+pub fn patch_assets(config: &Config) -> anyhow::Result<()> {
+    let mut files = HashMap::new();
+
+    match &config.frontend.dist_dir {
+        Some(dist_dir) => read_dir(&resolve_path(dist_dir)?, "", &mut files)?,
+
+        #[cfg(feature = "embed-frontend")]
+        None => add_embedded(&PIPED_SRC, &mut files),
+
+        #[cfg(not(feature = "embed-frontend"))]
+        None => {
+            return Err(anyhow!(
+                "frontend.dist_dir is required when built without the embed-frontend feature"
+            ))
+        }
+    }
+
+    let mut modified = HashSet::new();
+
+    if let Some(override_dir) = &config.frontend.override_dir {
+        let mut overrides = HashMap::new();
+        read_dir(&resolve_path(override_dir)?, "", &mut overrides)?;
+
+        modified.extend(overrides.keys().cloned());
+        files.extend(overrides);
+    }
 
-pub fn patch_assets(config: &Config) {
     // replace all matches to default backend isntance with new backend instance address
     // https://github.com/TeamPiped/Piped-Docker/blob/main/template/docker-compose.nginx.yml#L10
-    let patterns = &["https://pipedapi.kavin.rocks"];
-
-    // backend address
-    let address = if config.addresses.use_ssl.as_ref().is_some_and(|i| *i) {
-        // must use an ssl proxy since backend is always http
-        config.addresses.backend_ssl_proxy_uri().unwrap()
-    } else {
-        config.addresses.backend_uri()
+    let mut rules = vec![Rule {
+        pattern: Pattern::Literal(AhoCorasick::new(["https://pipedapi.kavin.rocks"])?),
+        from: "https://pipedapi.kavin.rocks",
+        // backend address
+        to: config.api_uri(),
+        files: None,
+        matches: 0,
+    }];
+
+    // the frontend gets its instance list from a public registry, serve our own list instead
+    let frontend_uri = config.addresses.frontend_uri();
+    for (registry, path) in [
+        (INSTANCE_REGISTRY, INSTANCES_PATH),
+        (INSTANCE_FALLBACK, INSTANCE_FALLBACK_PATH),
+    ] {
+        rules.push(Rule {
+            pattern: Pattern::Literal(AhoCorasick::new([registry])?),
+            from: registry,
+            to: format!("{frontend_uri}/{path}"),
+            files: None,
+            matches: 0,
+        });
+    }
+
+    for rewrite in &config.frontend.rewrites {
+        rules.push(Rule::new(rewrite)?);
+    }
+
+    for (path, file) in files.iter_mut() {
+        let Ok(mut contents) = std::str::from_utf8(file).map(str::to_string) else {
+            continue;
+        };
+
+        let mut changed = false;
+        for rule in rules.iter_mut() {
+            if let Some(replaced) = rule.apply(path, &contents) {
+                contents = replaced;
+                changed = true;
+            }
+        }
+
+        if changed {
+            *file = Box::leak(contents.into_bytes().into_boxed_slice());
+            modified.insert(path.clone());
+        }
+    }
+
+    // a rule not matching anything usually means a frontend update changed what it targets
+    for rule in rules.iter().filter(|rule| rule.matches == 0) {
+        eprintln!(
+            "warning: frontend rewrite `{}` didn't match anything",
+            rule.from
+        );
+    }
+
+    inject_defaults(config, &mut files)?;
+    add_instance_list(config, &mut files)?;
+    modified.extend(["index.html", INSTANCES_PATH, INSTANCE_FALLBACK_PATH].map(str::to_string));
+
+    FILES.set(files).unwrap();
+    MODIFIED.set(modified).unwrap();
+
+    Ok(())
+}
+
+/// Adds the instance list the frontend's registry requests are rewritten to, and the empty fallback
+fn add_instance_list(
+    config: &Config,
+    files: &mut HashMap<String, &'static [u8]>,
+) -> anyhow::Result<()> {
+    let frontend = &config.frontend;
+
+    // same shape as the public list
+    let instance = |name: &str, api_url: &str, image_proxy_url: &str, registration_disabled| {
+        json!({
+            "name": name,
+            "api_url": api_url,
+            "locations": "",
+            "version": "",
+            "up_to_date": true,
+            "cdn": false,
+            "registered": 0,
+            "last_checked": 0,
+            "cache": false,
+            "s3_enabled": false,
+            "image_proxy_url": image_proxy_url,
+            "registration_disabled": registration_disabled,
+        })
     };
 
-    let replace_with = &[&*address];
+    let mut instances = vec![instance(
+        frontend
+            .instance_name
+            .as_deref()
+            .unwrap_or("youtube-server"),
+        &config.api_uri(),
+        &config.addresses.proxy_uri(),
+        config.backend.disable_registration,
+    )];
+
+    for extra in frontend.extra_instances.iter().flatten() {
+        instances.push(instance(&extra.name, &extra.api_url, "", false));
+    }
 
-    let ac = AhoCorasick::new(patterns).unwrap();
+    let instances = serde_json::to_vec(&instances)?;
+    files.insert(
+        INSTANCES_PATH.to_string(),
+        Box::leak(instances.into_boxed_slice()),
+    );
+    files.insert(INSTANCE_FALLBACK_PATH.to_string(), b"");
 
-    let mut hashmap = HashMap::new();
+    Ok(())
+}
 
-    let dir = PIPED_SRC.get_dir("assets").unwrap();
-    for file in dir.files() {
-        if let Some(contents) = file.contents_utf8() {
-            let replaced = ac.replace_all(contents, replace_with);
-            if contents != replaced {
-                hashmap.insert(file.path().to_str().unwrap().to_string(), replaced);
+/// Adds a script to index.html which seeds local storage with the configured default preferences
+fn inject_defaults(
+    config: &Config,
+    files: &mut HashMap<String, &'static [u8]>,
+) -> anyhow::Result<()> {
+    let defaults = &config.frontend.defaults;
+
+    // the frontend stores everything as strings
+    let mut values = BTreeMap::new();
+    values.insert(
+        "instance",
+        defaults
+            .instance
+            .clone()
+            .unwrap_or_else(|| config.api_uri()),
+    );
+    if let Some(quality) = defaults.quality {
+        values.insert("quality", quality.to_string());
+    }
+    if let Some(region) = &defaults.region {
+        values.insert("region", region.clone());
+    }
+    if let Some(theme) = &defaults.theme {
+        values.insert("theme", theme.clone());
+    }
+    if let Some(sponsorblock) = defaults.sponsorblock {
+        values.insert("sponsorblock", sponsorblock.to_string());
+    }
+    if let Some(categories) = &defaults.sponsorblock_categories {
+        values.insert("selectedSkip", categories.join(","));
+    }
+    for (key, value) in defaults.extra.iter().flatten() {
+        values.insert(key, value.clone());
+    }
+
+    // `</` would end the script early
+    let values = serde_json::to_string(&values)?.replace("</", "<\\/");
+    let script = format!(
+        "try{{var d={values};for(var k in d)if(localStorage.getItem(k)===null)localStorage.setItem(k,d[k])}}catch(e){{}}"
+    );
+
+    let Some(index) = files.get_mut("index.html") else {
+        return Ok(());
+    };
+    let Ok(contents) = std::str::from_utf8(index) else {
+        return Ok(());
+    };
+
+    // has to run before the app's module scripts, which are deferred anyway
+    let contents = match contents.find("</head>") {
+        Some(pos) => format!(
+            "{}<script>{script}</script>{}",
+            &contents[..pos],
+            &contents[pos..]
+        ),
+        None => format!("<script>{script}</script>{contents}"),
+    };
+    *index = Box::leak(contents.into_bytes().into_boxed_slice());
+
+    // allows exactly this script through the content security policy
+    let hash = B64.encode(digest(&SHA256, script.as_bytes()));
+    INLINE_SCRIPT_HASH.set(format!("'sha256-{hash}'")).unwrap();
+
+    Ok(())
+}
+
+/// Csp source for the injected defaults script, if there is one
+pub fn inline_script_hash() -> Option<&'static str> {
+    INLINE_SCRIPT_HASH.get().map(|h| h.as_str())
+}
+
+enum Pattern {
+    Literal(AhoCorasick),
+    Regex(Regex),
+}
+
+struct Rule<'a> {
+    pattern: Pattern,
+    from: &'a str,
+    to: String,
+    files: Option<GlobSet>,
+    matches: usize,
+}
+
+impl<'a> Rule<'a> {
+    fn new(rewrite: &'a Rewrite) -> anyhow::Result<Self> {
+        let pattern = if rewrite.regex {
+            Pattern::Regex(Regex::new(&rewrite.from)?)
+        } else {
+            Pattern::Literal(AhoCorasick::new([&rewrite.from])?)
+        };
+
+        let files = match &rewrite.files {
+            Some(globs) => {
+                let mut builder = GlobSetBuilder::new();
+                for glob in globs {
+                    builder.add(Glob::new(glob)?);
+                }
+                Some(builder.build()?)
             }
+
+            None => None,
+        };
+
+        Ok(Self {
+            pattern,
+            from: &rewrite.from,
+            to: rewrite.to.clone(),
+            files,
+            matches: 0,
+        })
+    }
+
+    /// Rewrites `contents` of the file at `path`. Returns `None` if nothing matched
+    fn apply(&mut self, path: &str, contents: &str) -> Option<String> {
+        if self
+            .files
+            .as_ref()
+            .is_some_and(|files| !files.is_match(path))
+        {
+            return None;
+        }
+
+        let matches = match &self.pattern {
+            Pattern::Literal(ac) => ac.find_iter(contents).count(),
+            Pattern::Regex(regex) => regex.find_iter(contents).count(),
+        };
+
+        if matches == 0 {
+            return None;
+        }
+
+        self.matches += matches;
+
+        Some(match &self.pattern {
+            Pattern::Literal(ac) => ac.replace_all(contents, &[&self.to]),
+            Pattern::Regex(regex) => regex.replace_all(contents, &self.to).into_owned(),
+        })
+    }
+}
+
+#[cfg(feature = "embed-frontend")]
+fn add_embedded(dir: &Dir<'static>, files: &mut HashMap<String, &'static [u8]>) {
+    for file in dir.files() {
+        files.insert(file.path().to_str().unwrap().to_string(), file.contents());
+    }
+
+    for dir in dir.dirs() {
+        add_embedded(dir, files);
+    }
+}
+
+// files read from disk are loaded once and live as long as the embedded ones
+fn read_dir(
+    dir: &Path,
+    prefix: &str,
+    files: &mut HashMap<String, &'static [u8]>,
+) -> anyhow::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        let path = format!("{prefix}{name}");
+
+        if entry.file_type()?.is_dir() {
+            read_dir(&entry.path(), &format!("{path}/"), files)?;
+        } else {
+            let contents = fs::read(entry.path())?;
+            files.insert(path, Box::leak(contents.into_boxed_slice()));
         }
     }
 
-    ASSETS.set(hashmap).unwrap();
+    Ok(())
+}
+
+// gets a (possibly patched or overridden) frontend file
+pub fn get_file(target: &str) -> Option<&'static [u8]> {
+    FILES.get().unwrap().get(target).copied()
+}
+
+/// Whether a file is exactly as it is in the dist, so its hashed name still describes it
+pub fn is_unmodified(path: &str) -> bool {
+    !MODIFIED.get().unwrap().contains(path)
 }
 
-// gets a patched asset
-pub fn get_patched_asset(target: &str) -> Option<&'static str> {
-    ASSETS.get().unwrap().get(target).map(|i| &**i)
+/// All frontend files with their paths
+pub fn files() -> impl Iterator<Item = (&'static str, &'static [u8])> {
+    FILES
+        .get()
+        .unwrap()
+        .iter()
+        .map(|(path, contents)| (path.as_str(), *contents))
 }
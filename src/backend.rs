@@ -1,25 +1,58 @@
 use std::{
-    env, fs,
-    process::Command,
-    sync::{Arc, OnceLock},
+    convert::Infallible,
+    env::consts::EXE_SUFFIX,
+    fs,
+    path::{Path, PathBuf},
+    process::{Child, Command},
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
 };
+#[cfg(feature = "in-process-jvm")]
+use std::{env, thread};
 
 use anyhow::anyhow;
 use axum::{
     body::{Body, BoxBody, HttpBody},
-    http::Request,
+    http::{header, Request},
     response::Response,
 };
-use axum_server::tls_rustls::RustlsConfig;
 use directories::ProjectDirs;
 use reqwest::{redirect::Policy, Client, StatusCode};
-use serde_json::Value;
-use tokio::task::{self, JoinHandle};
+use tokio::{task, time};
 use tower::make::Shared;
 
-use crate::{config::Config, hash::JAR_HASH, resolver};
+#[cfg(feature = "in-process-jvm")]
+use crate::jvm;
+use crate::{
+    cds,
+    config::{self, Config},
+    instance, ports, sandbox,
+    tasks::Tasks,
+    tls,
+};
+#[cfg(feature = "embed-backend")]
+use crate::{files, hash::JAR_HASH};
 
 static REQUEST_DATA: OnceLock<RequestData> = OnceLock::new();
+// the java process, if one is running
+static BACKEND: Mutex<Option<Child>> = Mutex::new(None);
+
+// java flags for the backend, however it's run
+const JVM_OPTIONS: [&str; 8] = [
+    "-Xmx1G",
+    "-XX:+UnlockExperimentalVMOptions",
+    "-XX:+HeapDumpOnOutOfMemoryError",
+    "-XX:+OptimizeStringConcat",
+    "-XX:+UseStringDeduplication",
+    "-XX:+UseCompressedOops",
+    "-XX:+UseNUMA",
+    "-XX:+UseG1GC",
+];
+
+// how long to wait for the backend before giving up on reporting its startup time
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(300);
+// how long the backend gets to exit (and write its cds archive) before it's killed
+const STOP_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[derive(Debug)]
 struct RequestData {
@@ -27,114 +60,382 @@ struct RequestData {
     config: Arc<Config>,
 }
 
-pub fn run_backend(config: Arc<Config>) -> anyhow::Result<JoinHandle<anyhow::Result<()>>> {
-    let handle = task::spawn(async move {
+/// Adds the backend to `tasks`: the jvm (unless disabled), and the ssl proxy in front of it
+pub fn run_backend(config: Arc<Config>, tasks: &mut Tasks) -> anyhow::Result<()> {
+    // set up before spawning, the frontend may start forwarding to the backend right away
+    REQUEST_DATA
+        .set(RequestData {
+            // important, disable all redirects so we can be as transparent as possible
+            client: Client::builder().redirect(Policy::none()).build()?,
+            config: config.clone(),
+        })
+        .map_err(|_| anyhow!("backend already started"))?;
+
+    // a disabled backend runs elsewhere, so there may be nothing to do
+    if config.backend.disable && ssl_proxy(&config).is_none() {
+        return Ok(());
+    }
+
+    // a jvm can only be created once per process
+    if !config.backend.disable && config.backend.in_process_jvm.as_ref().is_some_and(|i| *i) {
+        tasks.spawn_once("backend", serve(config));
+    } else {
+        tasks.spawn("backend", move || serve(config.clone()));
+    }
+
+    Ok(())
+}
+
+// when mounted on the frontend, the frontend already covers ssl for us.
+// a remote backend without an ssl proxy is used directly
+fn ssl_proxy(config: &Config) -> Option<&str> {
+    config.addresses.backend_ssl_proxy.as_deref().filter(|_| {
+        config.addresses.use_ssl.as_ref().is_some_and(|i| *i)
+            && config.addresses.backend_prefix().is_none()
+    })
+}
+
+/// Runs the backend until it (or its ssl proxy) stops
+async fn serve(config: Arc<Config>) -> anyhow::Result<()> {
+    // a disabled backend runs elsewhere, so there's nothing to launch
+    let jvm = if config.backend.disable {
+        None
+    } else {
         // it would've been great to use graalvm for this and compile to a shared lib,
         // which I spent an entire day on, but it turns out there's just too many resource configs,
         // proxy configs, etc etc etc, it's too much and way too hard to get working.
         // at least the following works, you know?
 
-        let project_dir =
-            ProjectDirs::from("", "", "youtube-server").expect("Failed to get project directory");
+        let project_dir = ProjectDirs::from("", "", "youtube-server")
+            .ok_or(anyhow!("Failed to get project directory"))?;
 
         let data_local = project_dir.data_local_dir();
 
         if !data_local.exists() {
-            fs::create_dir_all(data_local).expect("Failed to create project dir");
+            fs::create_dir_all(data_local)?;
         }
 
-        let jar_path = data_local.join(format!("piped-{JAR_HASH}.jar"));
+        // hashing the jar and asking java for its version block for a while
+        let (launch_config, data_local) = (config.clone(), data_local.to_path_buf());
+        Some(task::spawn_blocking(move || launch(&launch_config, &data_local)).await??)
+    };
+
+    let Some(ssl_proxy) = ssl_proxy(&config) else {
+        return match jvm {
+            Some(jvm) => jvm.wait().await,
+            None => Ok(()),
+        };
+    };
+
+    // make backend address
+    let backend_addr = ports::listen_addr(
+        ssl_proxy,
+        config.addresses.use_ipv6.as_ref().is_some_and(|i| *i),
+    )?;
+
+    let serve_proxy = async {
+        let config = tls::rustls_config(&config, tls::Listener::BackendSslProxy).await?;
+
+        let service = tower::service_fn(backend_proxy);
+
+        axum_server::bind_rustls(backend_addr, config)
+            .serve(Shared::new(service))
+            .await?;
+
+        Ok(())
+    };
+
+    match jvm {
+        Some(jvm) => tokio::select! {
+            result = jvm.wait() => result,
+
+            result = serve_proxy => {
+                // don't leave the jvm running, a restart would start another one
+                task::spawn_blocking(stop).await?;
+                result
+            }
+        },
+
+        None => serve_proxy.await,
+    }
+}
+
+/// A running backend
+enum Jvm {
+    // the process itself is in `BACKEND`, so `stop` can get to it
+    Child,
+    #[cfg(feature = "in-process-jvm")]
+    InProcess(thread::JoinHandle<anyhow::Result<()>>),
+}
+
+impl Jvm {
+    /// Waits until the backend exits
+    async fn wait(self) -> anyhow::Result<()> {
+        match self {
+            // polled rather than waited on the blocking pool, which shutting down would wait on
+            Jvm::Child => loop {
+                {
+                    let mut backend = BACKEND.lock().unwrap();
+                    let Some(child) = backend.as_mut() else {
+                        // taken by `stop`
+                        return Ok(());
+                    };
+
+                    if let Some(status) = child.try_wait()? {
+                        *backend = None;
+
+                        if !status.success() {
+                            return Err(anyhow!("backend exited with {status}"));
+                        }
+
+                        return Ok(());
+                    }
+                }
 
-        if !jar_path.exists() {
-            fs::write(&jar_path, crate::PIPED_JAR).expect("Failed to write jar");
+                time::sleep(Duration::from_millis(500)).await;
+            },
+
+            // not joined on the blocking pool, since shutting down would wait on it forever.
+            // the jvm can't be stopped, it just ends with the process
+            #[cfg(feature = "in-process-jvm")]
+            Jvm::InProcess(handle) => {
+                while !handle.is_finished() {
+                    time::sleep(Duration::from_secs(1)).await;
+                }
+
+                handle
+                    .join()
+                    .map_err(|_| anyhow!("backend jvm thread panicked"))?
+            }
         }
+    }
+}
 
-        let jar_path = jar_path.to_str().ok_or(anyhow!("failed to make string"))?;
-
-        // java needs to be on PATH
-        let mut child = Command::new("java")
-            .args([
-                "-server",
-                "-Xmx1G",
-                "-XX:+UnlockExperimentalVMOptions",
-                "-XX:+HeapDumpOnOutOfMemoryError",
-                "-XX:+OptimizeStringConcat",
-                "-XX:+UseStringDeduplication",
-                "-XX:+UseCompressedOops",
-                "-XX:+UseNUMA",
-                "-XX:+UseG1GC",
-                "-jar",
-                jar_path,
-            ])
-            .current_dir(data_local)
-            .spawn()?;
-
-        if config.addresses.use_ssl.as_ref().is_some_and(|i| *i) {
-            REQUEST_DATA
-                .set(RequestData {
-                    // important, disable all redirects so we can be as transparent as possible
-                    client: Client::builder().redirect(Policy::none()).build().unwrap(),
-                    config: config.clone(),
-                })
-                .unwrap();
-
-            // make backend address
-            let backend_addr =
-                resolver::get_addresses(config.addresses.backend_ssl_proxy.as_ref().unwrap())
-                    .expect("Failed to resolve frontend address");
-            #[allow(clippy::if_same_then_else)]
-            let backend_addr = if config.addresses.use_ipv6.as_ref().is_some_and(|i| *i) {
-                backend_addr.ipv6.as_ref()
-            } else if backend_addr.ipv6.as_ref().is_some() {
-                backend_addr.ipv6.as_ref()
-            } else {
-                backend_addr.ipv4.as_ref()
+/// Starts the backend jar, as a child process or (with the in-process-jvm feature) inside this one
+fn launch(config: &Config, data_local: &Path) -> anyhow::Result<Jvm> {
+    let (jar_path, jar_hash) = match &config.backend.jar_path {
+        Some(jar_path) => {
+            let jar_path = config::resolve_path(jar_path)?;
+            let jar_hash = sha256_hex(&fs::read(&jar_path)?);
+            (jar_path, jar_hash)
+        }
+
+        None => embedded_jar(data_local)?,
+    };
+
+    let jar_path = jar_path.to_str().ok_or(anyhow!("failed to make string"))?;
+
+    let jre_dir = config
+        .backend
+        .jre_dir
+        .as_ref()
+        .map(config::resolve_path)
+        .transpose()?;
+
+    // without a jre_dir, java needs to be on PATH
+    let java = match &jre_dir {
+        Some(jre_dir) => jre_dir.join("bin").join(format!("java{EXE_SUFFIX}")),
+        None => PathBuf::from("java"),
+    };
+
+    let archive = if config.backend.disable_cds.as_ref().is_some_and(|i| *i) {
+        None
+    } else {
+        // the backend still runs fine without it, just slower
+        cds::Archive::prepare(data_local, &jar_hash, &java).unwrap_or_else(|e| {
+            eprintln!("warning: not using a class data sharing archive: {e}");
+            None
+        })
+    };
+
+    let mut options = JVM_OPTIONS.map(str::to_string).to_vec();
+    if let Some(archive) = &archive {
+        options.push(archive.jvm_arg());
+    }
+
+    let started = Instant::now();
+
+    let jvm = if config.backend.in_process_jvm.as_ref().is_some_and(|i| *i) {
+        if config.backend.sandbox.enabled() {
+            eprintln!("warning: the backend sandbox doesn't apply to in_process_jvm, ignoring it");
+        }
+
+        in_process(jre_dir, jar_path, options)?
+    } else {
+        let mut command = Command::new(&java);
+        command
+            .arg("-server")
+            .args(options)
+            .args(["-jar", jar_path])
+            .current_dir(data_local);
+
+        sandbox::apply(
+            &mut command,
+            &config.backend.sandbox,
+            &java,
+            Path::new(jar_path),
+            data_local,
+        )?;
+
+        let child = command.spawn()?;
+        *BACKEND.lock().unwrap() = Some(child);
+
+        Jvm::Child
+    };
+
+    task::spawn(report_startup(started, archive));
+
+    Ok(jvm)
+}
+
+#[cfg(feature = "in-process-jvm")]
+fn in_process(
+    jre_dir: Option<PathBuf>,
+    jar_path: &str,
+    options: Vec<String>,
+) -> anyhow::Result<Jvm> {
+    let jre_dir = jre_dir
+        .or_else(|| env::var_os("JAVA_HOME").map(PathBuf::from))
+        .ok_or(anyhow!(
+            "backend.jre_dir or JAVA_HOME is needed to run the jvm in process"
+        ))?;
+
+    Ok(Jvm::InProcess(jvm::start(&jre_dir, jar_path, options)?))
+}
+
+#[cfg(not(feature = "in-process-jvm"))]
+fn in_process(
+    _jre_dir: Option<PathBuf>,
+    _jar_path: &str,
+    _options: Vec<String>,
+) -> anyhow::Result<Jvm> {
+    Err(anyhow!(
+        "backend.in_process_jvm needs the in-process-jvm feature"
+    ))
+}
+
+/// Asks the java process to shut down, if one was started, and waits until it has (killing it
+/// if it takes too long). An in process jvm just exits with us
+pub fn stop() {
+    let Some(mut child) = BACKEND.lock().unwrap().take() else {
+        return;
+    };
+
+    if let Err(e) = instance::terminate(child.id()) {
+        eprintln!("warning: failed to stop the backend: {e}");
+    }
+
+    let started = Instant::now();
+    while started.elapsed() < STOP_TIMEOUT {
+        match child.try_wait() {
+            Ok(Some(_)) => return,
+            Ok(None) => std::thread::sleep(Duration::from_millis(100)),
+            Err(e) => {
+                eprintln!("warning: failed to wait for the backend: {e}");
+                break;
             }
-            .expect("Failed to resolve frontend address");
-
-            // get server config for rust
-            let exe_path = env::current_exe()?;
-            let exe_path = exe_path.parent().ok_or(anyhow!("Failed to get parent"))?;
-
-            let config = RustlsConfig::from_pem_file(
-                exe_path.join(
-                    config
-                        .addresses
-                        .ssl_cert
-                        .as_ref()
-                        .ok_or(anyhow!("ssl_cert missing"))?,
-                ),
-                exe_path.join(
-                    config
-                        .addresses
-                        .ssl_key
-                        .as_ref()
-                        .ok_or(anyhow!("ssl_key missing"))?,
-                ),
-            )
-            .await?;
-            //
-
-            let service = tower::service_fn(backend_ssl_proxy);
-
-            axum_server::bind_rustls(*backend_addr, config)
-                .serve(Shared::new(service))
-                .await?;
-        } else {
-            task::spawn_blocking(move || {
-                child.wait()?;
-                Ok::<_, anyhow::Error>(())
-            })
-            .await??;
         }
+    }
 
-        Ok(())
-    });
+    eprintln!("warning: the backend didn't stop in {STOP_TIMEOUT:?}, killing it");
+    child.kill().ok();
+    child.wait().ok();
+}
+
+/// Logs how long the backend took to start answering requests
+async fn report_startup(started: Instant, archive: Option<cds::Archive>) {
+    let data = REQUEST_DATA.get().unwrap();
+    let backend_uri = data.config.addresses.backend_uri();
+
+    // any response at all means it's up
+    while data.client.get(&backend_uri).send().await.is_err() {
+        if started.elapsed() > STARTUP_TIMEOUT {
+            eprintln!("warning: backend hasn't started after {STARTUP_TIMEOUT:?}");
+            return;
+        }
+
+        time::sleep(Duration::from_millis(250)).await;
+    }
 
-    Ok(handle)
+    let cds = match archive {
+        Some(archive) if archive.exists() => "using class data sharing archive",
+        Some(_) => "class data sharing archive is made when it exits cleanly",
+        None => "no class data sharing",
+    };
+
+    println!("Backend started in {:.2?} ({cds})", started.elapsed());
 }
 
-async fn backend_ssl_proxy(req: Request<Body>) -> anyhow::Result<Response<BoxBody>> {
+/// Writes the embedded jar to the data dir, unless an intact copy is already there,
+/// and removes the jars of other versions. Returns its path and hash
+#[cfg(feature = "embed-backend")]
+fn embedded_jar(data_local: &Path) -> anyhow::Result<(PathBuf, String)> {
+    let jar_path = data_local.join(format!("piped-{JAR_HASH}.jar"));
+
+    // a copy from an interrupted write or a corrupted disk would never get replaced otherwise
+    let intact = fs::read(&jar_path).is_ok_and(|jar| sha256_hex(&jar) == JAR_HASH);
+
+    if !intact {
+        files::write_atomic(&jar_path, crate::PIPED_JAR, None)?;
+    }
+
+    remove_stale_jars(data_local, &jar_path);
+
+    Ok((jar_path, JAR_HASH.to_string()))
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    ring::digest::digest(&ring::digest::SHA256, data)
+        .as_ref()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Removes jars (and leftover temp files) from previous versions
+#[cfg(feature = "embed-backend")]
+fn remove_stale_jars(data_local: &Path, current: &Path) {
+    let Ok(entries) = fs::read_dir(data_local) else {
+        return;
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+
+        let is_jar =
+            name.starts_with("piped-") && (name.ends_with(".jar") || name.ends_with(".tmp"));
+
+        if is_jar && path != current {
+            if let Err(e) = fs::remove_file(&path) {
+                eprintln!(
+                    "warning: failed to remove old backend jar {}: {e}",
+                    path.display()
+                );
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "embed-backend"))]
+fn embedded_jar(_data_local: &Path) -> anyhow::Result<(PathBuf, String)> {
+    Err(anyhow!(
+        "backend.jar_path is required when built without the embed-backend feature"
+    ))
+}
+
+/// Forwards a request to the http backend. Used for both the ssl proxy and the frontend mount
+/// (where the mount path is already stripped off the uri)
+pub async fn backend_proxy(req: Request<Body>) -> Result<Response<BoxBody>, Infallible> {
+    let data = REQUEST_DATA.get().unwrap();
+
+    Ok(forward(&data.config.backend_upstream(), req).await)
+}
+
+/// Forwards a request as-is to `base` (an address like `http://127.0.0.1:8081`),
+/// keeping its path and query
+async fn forward(base: &str, req: Request<Body>) -> Response<BoxBody> {
     let data = REQUEST_DATA.get().unwrap();
 
     let (parts, body) = req.into_parts();
@@ -145,9 +446,11 @@ async fn backend_ssl_proxy(req: Request<Body>) -> anyhow::Result<Response<BoxBod
         .map(|i| i.as_str())
         .unwrap_or("/");
     let method = parts.method;
-    let headers = parts.headers;
+    let mut headers = parts.headers;
+    // the upstream may be a remote host, which needs its own name in the host header
+    headers.remove(header::HOST);
 
-    let url = format!("{}{path}", data.config.addresses.backend_uri());
+    let url = format!("{base}{path}");
 
     let reqwest = match data
         .client
@@ -159,8 +462,8 @@ async fn backend_ssl_proxy(req: Request<Body>) -> anyhow::Result<Response<BoxBod
     {
         Ok(res) => res,
         Err(e) => {
-            let error: Value = serde_json::from_str(&format!("{e:?}")).unwrap();
-            let error = serde_json::to_string(&error).unwrap();
+            // serializing a string gives us a properly escaped json string
+            let error = serde_json::to_string(&format!("Reqwest error: {e:?}")).unwrap();
 
             let status_code = if let Some(code) = e.status() {
                 code
@@ -172,11 +475,12 @@ async fn backend_ssl_proxy(req: Request<Body>) -> anyhow::Result<Response<BoxBod
                 StatusCode::INTERNAL_SERVER_ERROR
             };
 
-            return Ok(Response::builder()
+            return Response::builder()
                 .status(status_code)
-                .body(format!(r#"{{"error":"Reqwest error: {error}"}}"#))
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(format!(r#"{{"error":{error}}}"#))
                 .unwrap()
-                .map(|b| BoxBody::new(b.map_err(axum::Error::new))));
+                .map(|b| BoxBody::new(b.map_err(axum::Error::new)));
         }
     };
 
@@ -184,11 +488,9 @@ async fn backend_ssl_proxy(req: Request<Body>) -> anyhow::Result<Response<BoxBod
 
     *response.headers_mut().unwrap() = reqwest.headers().clone();
 
-    let response = response
+    response
         .status(reqwest.status())
         .body(Body::wrap_stream(reqwest.bytes_stream()))
         .unwrap()
-        .map(|b| BoxBody::new(b.map_err(axum::Error::new)));
-
-    Ok(response)
+        .map(|b| BoxBody::new(b.map_err(axum::Error::new)))
 }
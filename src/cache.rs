@@ -0,0 +1,222 @@
+use std::{
+    collections::HashMap,
+    sync::OnceLock,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::http::{header, HeaderMap, HeaderValue};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as B64, Engine};
+use ring::digest::{digest, SHA256};
+
+use crate::assets;
+
+static ETAGS: OnceLock<HashMap<String, String>> = OnceLock::new();
+// embedded files have no modification time, so use when we started serving them
+static LAST_MODIFIED: OnceLock<SystemTime> = OnceLock::new();
+
+// files under assets/ have a content hash in their name, so they never change.
+// Unless we patched or overrode them, then the hash no longer matches what's served
+const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+const REVALIDATE: &str = "no-cache";
+
+/// Computes strong etags for every frontend file, patched and overridden ones included.
+/// Must run after `assets::patch_assets`
+pub fn compute_etags() {
+    let etags = assets::files()
+        .map(|(path, contents)| (path.to_string(), etag(contents)))
+        .collect::<HashMap<_, _>>();
+
+    ETAGS.set(etags).unwrap();
+
+    // http dates only have second precision
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    LAST_MODIFIED
+        .set(UNIX_EPOCH + Duration::from_secs(now))
+        .unwrap();
+}
+
+fn etag(contents: &[u8]) -> String {
+    let hash = digest(&SHA256, contents);
+    format!("\"{}\"", B64.encode(&hash.as_ref()[..16]))
+}
+
+/// Adds the caching headers for `path` to `response`, and returns whether the client's
+/// cached copy is still fresh, in which case a 304 should be sent instead.
+/// `contents` is only hashed for files which weren't known at startup
+pub fn is_fresh(
+    path: &str,
+    encoding: Option<&str>,
+    contents: &[u8],
+    request: &HeaderMap,
+    response: &mut HeaderMap,
+) -> bool {
+    let etag = ETAGS
+        .get()
+        .and_then(|etags| etags.get(path).cloned())
+        .unwrap_or_else(|| etag(contents));
+
+    // every encoding is a different representation, so needs its own etag
+    let etag = match encoding {
+        Some(encoding) => format!("{}-{encoding}\"", etag.trim_end_matches('"')),
+        None => etag,
+    };
+    let last_modified = *LAST_MODIFIED.get().unwrap();
+
+    let cache_control = if path.starts_with("assets/") && assets::is_unmodified(path) {
+        IMMUTABLE
+    } else {
+        REVALIDATE
+    };
+
+    response.insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_static(cache_control),
+    );
+    response.insert(
+        header::LAST_MODIFIED,
+        HeaderValue::from_str(&httpdate::fmt_http_date(last_modified)).unwrap(),
+    );
+    response.insert(header::ETAG, HeaderValue::from_str(&etag).unwrap());
+
+    // If-None-Match takes precedence, https://www.rfc-editor.org/rfc/rfc9110#section-13.2.2
+    if let Some(if_none_match) = request
+        .get(header::IF_NONE_MATCH)
+        .and_then(|h| h.to_str().ok())
+    {
+        return if_none_match.split(',').any(|tag| {
+            let tag = tag.trim();
+            // weak comparison is used for If-None-Match
+            tag == "*" || tag.trim_start_matches("W/") == etag
+        });
+    }
+
+    request
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|since| httpdate::parse_http_date(since).ok())
+        .is_some_and(|since| last_modified <= since)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONTENTS: &[u8] = b"<html></html>";
+
+    fn check(encoding: Option<&str>, conditions: &[(header::HeaderName, &str)]) -> bool {
+        LAST_MODIFIED.get_or_init(|| UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+
+        let mut request = HeaderMap::new();
+        for (name, value) in conditions {
+            request.insert(name, HeaderValue::from_str(value).unwrap());
+        }
+
+        is_fresh(
+            "index.html",
+            encoding,
+            CONTENTS,
+            &request,
+            &mut HeaderMap::new(),
+        )
+    }
+
+    fn if_none_match(value: &str) -> (header::HeaderName, &str) {
+        (header::IF_NONE_MATCH, value)
+    }
+
+    // the tag for `encoding`, eg: "abc-br"
+    fn tag(encoding: &str) -> String {
+        format!("{}-{encoding}\"", etag(CONTENTS).trim_end_matches('"'))
+    }
+
+    #[test]
+    fn unconditional_is_stale() {
+        assert!(!check(None, &[]));
+    }
+
+    #[test]
+    fn sets_caching_headers() {
+        LAST_MODIFIED.get_or_init(|| UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+
+        let mut response = HeaderMap::new();
+        is_fresh(
+            "index.html",
+            Some("br"),
+            CONTENTS,
+            &HeaderMap::new(),
+            &mut response,
+        );
+
+        assert_eq!(response[header::ETAG], tag("br"));
+        assert_eq!(response[header::CACHE_CONTROL], REVALIDATE);
+        assert!(response.contains_key(header::LAST_MODIFIED));
+    }
+
+    #[test]
+    fn if_none_match_compares_tags() {
+        let etag = etag(CONTENTS);
+
+        assert!(check(None, &[if_none_match(&etag)]));
+        assert!(check(None, &[if_none_match(&format!("\"other\", {etag}"))]));
+        assert!(!check(None, &[if_none_match("\"other\"")]));
+    }
+
+    #[test]
+    fn if_none_match_star() {
+        assert!(check(None, &[if_none_match("*")]));
+        assert!(check(Some("gzip"), &[if_none_match("*")]));
+    }
+
+    #[test]
+    fn if_none_match_weak() {
+        let weak = format!("W/{}", etag(CONTENTS));
+
+        assert!(check(None, &[if_none_match(&weak)]));
+    }
+
+    #[test]
+    fn if_none_match_per_encoding() {
+        assert!(check(Some("br"), &[if_none_match(&tag("br"))]));
+        assert!(check(
+            Some("br"),
+            &[if_none_match(&format!("W/{}", tag("br")))]
+        ));
+        // a cached copy in another encoding, or the uncompressed one, is a different representation
+        assert!(!check(Some("br"), &[if_none_match(&tag("gzip"))]));
+        assert!(!check(Some("br"), &[if_none_match(&etag(CONTENTS))]));
+        assert!(!check(None, &[if_none_match(&tag("br"))]));
+    }
+
+    #[test]
+    fn if_modified_since() {
+        let later = httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(1_800_000_000));
+        let earlier = httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(1_600_000_000));
+
+        assert!(check(None, &[(header::IF_MODIFIED_SINCE, &later)]));
+        assert!(!check(None, &[(header::IF_MODIFIED_SINCE, &earlier)]));
+    }
+
+    #[test]
+    fn if_none_match_takes_precedence() {
+        let later = httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(1_800_000_000));
+        let earlier = httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(1_600_000_000));
+
+        assert!(!check(
+            None,
+            &[
+                if_none_match("\"other\""),
+                (header::IF_MODIFIED_SINCE, &later)
+            ]
+        ));
+        assert!(check(
+            None,
+            &[
+                if_none_match(&etag(CONTENTS)),
+                (header::IF_MODIFIED_SINCE, &earlier)
+            ]
+        ));
+    }
+}
@@ -0,0 +1,93 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::anyhow;
+use ring::digest::{digest, SHA256};
+
+// dynamic archives (ArchiveClassesAtExit) were added in java 13
+const MIN_JAVA: u32 = 13;
+
+/// A class data sharing archive of the backend's classes, which makes the jvm start faster.
+/// It's only valid for the exact jar and java version it was made with
+#[derive(Debug)]
+pub struct Archive {
+    path: PathBuf,
+    exists: bool,
+}
+
+impl Archive {
+    /// Finds the archive for this jar and `java`, and removes any others.
+    /// Returns `None` if java is too old to make one
+    pub fn prepare(data_local: &Path, jar_hash: &str, java: &Path) -> anyhow::Result<Option<Self>> {
+        let version = java_version(java)?;
+
+        if major_version(&version).is_some_and(|major| major < MIN_JAVA) {
+            return Ok(None);
+        }
+
+        // any change to the jar or java (even a patch release) needs a new archive
+        let key = digest(&SHA256, format!("{jar_hash}\n{version}").as_bytes());
+        let key = key.as_ref()[..8]
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<String>();
+
+        let cds_dir = data_local.join("cds");
+        fs::create_dir_all(&cds_dir)?;
+
+        let path = cds_dir.join(format!("piped-{key}.jsa"));
+
+        for entry in fs::read_dir(&cds_dir)?.flatten() {
+            if entry.path() != path {
+                fs::remove_file(entry.path()).ok();
+            }
+        }
+
+        Ok(Some(Self {
+            exists: path.exists(),
+            path,
+        }))
+    }
+
+    /// Jvm option to use the archive, or to create it when the backend exits if there isn't one yet
+    pub fn jvm_arg(&self) -> String {
+        if self.exists {
+            format!("-XX:SharedArchiveFile={}", self.path.display())
+        } else {
+            format!("-XX:ArchiveClassesAtExit={}", self.path.display())
+        }
+    }
+
+    pub fn exists(&self) -> bool {
+        self.exists
+    }
+}
+
+// eg: openjdk version "17.0.8" 2023-07-18
+//     OpenJDK Runtime Environment Temurin-17.0.8+7 (build 17.0.8+7)
+fn java_version(java: &Path) -> anyhow::Result<String> {
+    let output = Command::new(java).arg("-version").output()?;
+
+    // java prints its version to stderr
+    let version = String::from_utf8_lossy(&output.stderr).trim().to_string();
+    if !output.status.success() || version.is_empty() {
+        return Err(anyhow!("Failed to get java version"));
+    }
+
+    Ok(version)
+}
+
+fn major_version(version: &str) -> Option<u32> {
+    let start = version.find('"')? + 1;
+    let version = &version[start..];
+
+    let mut parts = version.split(|c: char| !c.is_ascii_digit());
+    match parts.next()?.parse::<u32>().ok()? {
+        // old java versions are 1.x
+        1 => parts.next()?.parse().ok(),
+        major => Some(major),
+    }
+}
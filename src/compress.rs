@@ -0,0 +1,229 @@
+use std::{
+    collections::HashMap,
+    io::Write,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, OnceLock,
+    },
+    thread,
+};
+
+use axum::http::{header, HeaderMap};
+use flate2::{write::GzEncoder, Compression};
+
+use crate::assets;
+
+static COMPRESSED: OnceLock<HashMap<String, Variants>> = OnceLock::new();
+
+// in order of preference when the client accepts several
+const ENCODINGS: [&str; 3] = ["br", "zstd", "gzip"];
+
+#[derive(Debug, Default)]
+struct Variants {
+    br: Option<Vec<u8>>,
+    zstd: Option<Vec<u8>>,
+    gzip: Option<Vec<u8>>,
+}
+
+impl Variants {
+    fn get(&self, encoding: &str) -> Option<&[u8]> {
+        match encoding {
+            "br" => self.br.as_deref(),
+            "zstd" => self.zstd.as_deref(),
+            "gzip" => self.gzip.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+/// Compresses every text file of the frontend (patched and overridden ones included) with brotli, zstd and gzip.
+/// Must run after `assets::patch_assets`. Files are served uncompressed until it's done
+pub fn compress_assets() {
+    let files = assets::files()
+        .filter(|(_, contents)| std::str::from_utf8(contents).is_ok())
+        .collect::<Vec<_>>();
+
+    let compressed = Mutex::new(HashMap::new());
+    let next = AtomicUsize::new(0);
+
+    // the high levels are slow, so spread the files over all cores
+    let workers = thread::available_parallelism().map_or(1, |n| n.get());
+    thread::scope(|s| {
+        for _ in 0..workers {
+            s.spawn(|| {
+                while let Some((path, contents)) = files.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    let variants = compress(contents);
+                    compressed
+                        .lock()
+                        .unwrap()
+                        .insert(path.to_string(), variants);
+                }
+            });
+        }
+    });
+
+    COMPRESSED.set(compressed.into_inner().unwrap()).unwrap();
+}
+
+fn compress(contents: &[u8]) -> Variants {
+    let br = {
+        let mut out = Vec::new();
+        {
+            let mut writer = brotli::CompressorWriter::new(&mut out, 4096, 9, 22);
+            writer.write_all(contents).unwrap();
+        }
+        out
+    };
+
+    let zstd = zstd::encode_all(contents, 15).unwrap();
+
+    let gzip = {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(contents).unwrap();
+        encoder.finish().unwrap()
+    };
+
+    // tiny files can end up bigger, those are better sent as is
+    let smaller = |data: Vec<u8>| (data.len() < contents.len()).then_some(data);
+
+    Variants {
+        br: smaller(br),
+        zstd: smaller(zstd),
+        gzip: smaller(gzip),
+    }
+}
+
+/// Whether `path` has compressed variants, in which case responses must `Vary: Accept-Encoding`
+pub fn is_compressed(path: &str) -> bool {
+    match COMPRESSED.get() {
+        Some(compressed) => compressed.contains_key(path),
+        // it may be soon
+        None => true,
+    }
+}
+
+/// Picks the best precompressed variant of `path` the client accepts.
+/// Returns the content encoding and the compressed data
+pub fn negotiate(path: &str, request: &HeaderMap) -> Option<(&'static str, &'static [u8])> {
+    let variants = COMPRESSED.get()?.get(path)?;
+
+    let accept = request
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|h| h.to_str().ok())?;
+
+    // eg: `gzip, deflate, br;q=1.0, zstd;q=0`
+    let accepted = accept
+        .split(',')
+        .filter_map(|coding| {
+            let mut params = coding.split(';');
+            let name = params.next()?.trim().to_ascii_lowercase();
+            let q = params
+                .find_map(|p| p.trim().strip_prefix("q="))
+                .map_or(1.0, |q| q.trim().parse::<f32>().unwrap_or(0.0));
+
+            Some((name, q))
+        })
+        .collect::<Vec<_>>();
+
+    let quality = |encoding: &str| {
+        accepted
+            .iter()
+            .find(|(name, _)| name == encoding)
+            .or_else(|| accepted.iter().find(|(name, _)| name == "*"))
+            .map_or(0.0, |(_, q)| *q)
+    };
+
+    ENCODINGS
+        .iter()
+        .filter(|encoding| quality(encoding) > 0.0)
+        .find_map(|encoding| Some((*encoding, variants.get(encoding)?)))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn negotiate_for(path: &str, accept: Option<&str>) -> Option<&'static str> {
+        COMPRESSED.get_or_init(|| {
+            let all = Variants {
+                br: Some(b"br".to_vec()),
+                zstd: Some(b"zstd".to_vec()),
+                gzip: Some(b"gzip".to_vec()),
+            };
+            // eg: a file where brotli and zstd weren't smaller
+            let gzip_only = Variants {
+                gzip: Some(b"gzip".to_vec()),
+                ..Default::default()
+            };
+
+            HashMap::from([
+                ("all.js".to_string(), all),
+                ("gzip.js".to_string(), gzip_only),
+            ])
+        });
+
+        let mut request = HeaderMap::new();
+        if let Some(accept) = accept {
+            request.insert(
+                header::ACCEPT_ENCODING,
+                HeaderValue::from_str(accept).unwrap(),
+            );
+        }
+
+        negotiate(path, &request).map(|(encoding, data)| {
+            assert_eq!(encoding.as_bytes(), data);
+            encoding
+        })
+    }
+
+    #[test]
+    fn prefers_brotli() {
+        assert_eq!(
+            negotiate_for("all.js", Some("gzip, deflate, br, zstd")),
+            Some("br")
+        );
+        assert_eq!(negotiate_for("all.js", Some("gzip, zstd")), Some("zstd"));
+        assert_eq!(negotiate_for("all.js", Some("GZIP")), Some("gzip"));
+    }
+
+    #[test]
+    fn zero_quality_is_refused() {
+        assert_eq!(negotiate_for("all.js", Some("br;q=0, gzip")), Some("gzip"));
+        assert_eq!(
+            negotiate_for("all.js", Some("br; q=0.0, zstd;q=0.5, gzip;q=1")),
+            Some("zstd")
+        );
+        assert_eq!(
+            negotiate_for("all.js", Some("br;q=0, zstd;q=0, gzip;q=0")),
+            None
+        );
+        // an unparsable quality counts as not accepted
+        assert_eq!(negotiate_for("all.js", Some("br;q=x, gzip")), Some("gzip"));
+    }
+
+    #[test]
+    fn star_matches_the_rest() {
+        assert_eq!(negotiate_for("all.js", Some("*")), Some("br"));
+        assert_eq!(negotiate_for("all.js", Some("br;q=0, *")), Some("zstd"));
+        assert_eq!(negotiate_for("all.js", Some("*;q=0, gzip")), Some("gzip"));
+        assert_eq!(negotiate_for("all.js", Some("*;q=0")), None);
+    }
+
+    #[test]
+    fn missing_variants_are_skipped() {
+        assert_eq!(
+            negotiate_for("gzip.js", Some("br, zstd, gzip")),
+            Some("gzip")
+        );
+        assert_eq!(negotiate_for("gzip.js", Some("br, zstd")), None);
+    }
+
+    #[test]
+    fn uncompressed() {
+        assert_eq!(negotiate_for("all.js", None), None);
+        assert_eq!(negotiate_for("all.js", Some("identity")), None);
+        assert_eq!(negotiate_for("other.js", Some("br")), None);
+    }
+}
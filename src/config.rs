@@ -1,42 +1,125 @@
-use std::{env, fs};
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use anyhow::anyhow;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct Config {
     pub addresses: Addresses,
     pub backend: Backend,
+    #[serde(default)]
+    pub frontend: Frontend,
+    #[serde(default)]
+    pub acme: Acme,
+    #[serde(default)]
+    pub supervisor: Supervisor,
 }
 
 impl Config {
-    pub fn get_config() -> anyhow::Result<Self> {
+    /// The config lives in the same folder as the executable
+    pub fn config_path() -> anyhow::Result<PathBuf> {
         let current_folder = env::current_exe()?;
-        let config_path = current_folder
+        Ok(current_folder
             .parent()
             .ok_or(anyhow!("failed to get path"))?
-            .join("config.toml");
+            .join("config.toml"))
+    }
+
+    pub fn save(&self) -> anyhow::Result<()> {
+        let mut data = toml::to_string(self)?;
+        data.insert_str(0, "# For more options, please see `config.rs` and/or\n# https://github.com/TeamPiped/Piped-Backend/blob/master/config.properties\n\n");
+        fs::write(Self::config_path()?, data)?;
+        Ok(())
+    }
+
+    /// The external backend's api, if the embedded one is disabled in favour of it
+    pub fn remote_api(&self) -> Option<&str> {
+        if !self.backend.disable {
+            return None;
+        }
+
+        self.backend
+            .remote_api_url
+            .as_deref()
+            .map(|url| url.trim_end_matches('/'))
+    }
+
+    /// Where backend requests from the frontend mount and ssl proxy are forwarded to
+    pub fn backend_upstream(&self) -> String {
+        self.remote_api()
+            .map(str::to_string)
+            .unwrap_or_else(|| self.addresses.backend_uri())
+    }
+
+    /// The public address clients use to reach the backend api
+    pub fn api_uri(&self) -> String {
+        let addresses = &self.addresses;
+
+        match self.remote_api() {
+            // nothing to forward through, so the frontend can use the remote api directly
+            Some(remote)
+                if addresses.backend_prefix().is_none()
+                    && (!addresses.use_ssl.as_ref().is_some_and(|i| *i)
+                        || addresses.backend_ssl_proxy.is_none()) =>
+            {
+                remote.to_string()
+            }
+
+            _ => addresses.api_uri(),
+        }
+    }
 
-        let config = if let Ok(data) = fs::read_to_string(&config_path) {
+    pub fn get_config() -> anyhow::Result<Self> {
+        let config = if let Ok(data) = fs::read_to_string(Self::config_path()?) {
             toml::from_str::<Self>(&data)?
         } else {
             let cfg = Self::default();
-            let mut data = toml::to_string(&cfg)?;
-            data.insert_str(0, "# For more options, please see `config.rs` and/or\n# https://github.com/TeamPiped/Piped-Backend/blob/master/config.properties\n\n");
-            fs::write(config_path, data)?;
+            cfg.save()?;
             cfg
         };
 
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Catches settings which would otherwise only fail once the servers start
+    fn validate(&self) -> anyhow::Result<()> {
+        let addresses = &self.addresses;
+        addresses.validate()?;
+
+        // the backend is always http, so ssl clients need some https way to reach it
+        if addresses.use_ssl.as_ref().is_some_and(|i| *i)
+            && addresses.backend_prefix().is_none()
+            && addresses.backend_ssl_proxy.is_none()
+            && self.remote_api().is_none()
+        {
+            return Err(anyhow!(
+                "addresses.use_ssl needs backend_ssl_proxy, backend_path or single_port to serve the backend over https"
+            ));
+        }
+
+        for rewrite in &self.frontend.rewrites {
+            rewrite.validate()?;
+        }
+
+        Ok(())
+    }
+
+    /// Set the env vars the backend is configured with.
+    /// Must be called once the addresses are final (see `ports::preflight`)
+    pub fn set_env(&self) -> anyhow::Result<()> {
+        let config = self;
+
         // set env vars for backend
         env::set_var("DISABLE_SERVER", config.backend.disable_server.to_string());
-        let port = config
-            .addresses
-            .backend
-            .split(':')
-            .last()
-            .map(|l| l.parse::<u16>().unwrap_or(80))
-            .ok_or(anyhow!("http part missing"))?;
-        env::set_var("PORT", port.to_string());
+        env::set_var("PORT", config.addresses.backend_port()?.to_string());
         env::set_var("HTTP_WORKERS", config.backend.http_workers.to_string());
         env::set_var("PROXY_PART", config.addresses.proxy_uri());
         if let Some(image_proxy) = &config.backend.image_proxy_part {
@@ -48,7 +131,7 @@ impl Config {
         if let Some(api_key) = &config.backend.captcha_api_key {
             env::set_var("CAPTCHA_API_KEY", api_key);
         }
-        env::set_var("API_URL", config.addresses.backend_uri());
+        env::set_var("API_URL", config.api_uri());
         env::set_var("FRONTEND_URL", config.addresses.frontend_uri());
         if let Some(url) = &config.backend.pubsub_url {
             env::set_var("PUBSUB_URL", url);
@@ -124,13 +207,46 @@ impl Config {
                 .unwrap_or("org.hibernate.dialect.PostgreSQLDialect"),
         );
 
-        Ok(config)
+        Ok(())
     }
 }
 
+/// Relative paths in the config are relative to the executable, same as the config itself
+pub fn resolve_path(path: impl AsRef<Path>) -> anyhow::Result<PathBuf> {
+    let exe_path = env::current_exe()?;
+    let exe_path = exe_path.parent().ok_or(anyhow!("Failed to get parent"))?;
+
+    Ok(exe_path.join(path))
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Backend {
+    // Don't run the embedded backend (no java needed). The frontend then uses `remote_api_url`,
+    // or a backend you run yourself at the `backend` address
     pub disable: bool,
+    // Api of an external Piped backend to use when `disable` is set
+    // Requests are forwarded to it by `backend_path` and `backend_ssl_proxy` if set, otherwise
+    // the frontend talks to it directly. Its own PROXY_PART decides which proxy videos go through
+    //- eg: https://pipedapi.kavin.rocks
+    pub remote_api_url: Option<String>,
+    // Backend jar to run instead of the embedded one. Required when built without the
+    // embed-backend feature. Relative paths are relative to the executable
+    //- eg: piped-1.0-all.jar
+    pub jar_path: Option<String>,
+    // Don't use a class data sharing archive for the backend. One is made the first time a
+    // backend version runs (java 13+), which makes later starts faster.
+    // The archive is written when the backend exits cleanly, which on windows it never does
+    // (it's killed on shutdown), so there it's effectively unix only
+    pub disable_cds: Option<bool>,
+    // Java runtime to run the backend with instead of the java on PATH, eg: one shipped next to
+    // the executable. Relative paths are relative to the executable
+    //- eg: jre
+    pub jre_dir: Option<String>,
+    // Run the backend's jvm inside this process instead of as a child process.
+    // Needs the in-process-jvm feature, and `jre_dir` (or JAVA_HOME)
+    pub in_process_jvm: Option<bool>,
+    #[serde(default)]
+    pub sandbox: Sandbox,
     // Disable API server (node just runs timers if enabled)
     pub disable_server: bool,
     // The number of workers to use for the server
@@ -188,6 +304,12 @@ impl Default for Backend {
     fn default() -> Self {
         Self {
             disable: false,
+            remote_api_url: None,
+            jar_path: None,
+            disable_cds: None,
+            jre_dir: None,
+            in_process_jvm: None,
+            sandbox: Sandbox::default(),
             disable_server: false,
             http_workers: 2,
             captcha_base_url: None,
@@ -222,6 +344,45 @@ impl Default for Backend {
     }
 }
 
+// Hardening for the backend's java process. Linux only, and everything is off by default
+// Doesn't apply to in_process_jvm
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Sandbox {
+    // Only let it access the data dir, its jar and jre, and system dirs. Uses landlock
+    // (linux 5.13+), and turns on no_new_privs, which landlock needs
+    // Readable: /usr, /lib, /lib64, /bin, /etc, /run, /proc, /sys, the jre and the jar
+    // Writable: /tmp, /dev, the data dir and landlock_paths
+    pub landlock: Option<bool>,
+    // Extra paths it may use with landlock on, eg: a hsqldb database outside the data dir
+    pub landlock_paths: Option<Vec<String>>,
+    // Stop it from ever gaining privileges, eg: through setuid programs
+    pub no_new_privs: Option<bool>,
+    // Max virtual memory in MiB. The jvm reserves a lot more than it uses, so keep this well
+    // above its 1G heap
+    //- eg: 4096
+    pub memory_limit_mb: Option<u64>,
+    // Max open files (and sockets)
+    //- eg: 4096
+    pub open_files_limit: Option<u64>,
+    // Run it as this user (only works when running as root). It needs access to the data dir
+    //- eg: piped
+    pub user: Option<String>,
+    // Run it in its own process group, so signals meant for this one (eg: ctrl+c) don't reach it.
+    // It's still stopped when this process exits
+    pub process_group: Option<bool>,
+}
+
+impl Sandbox {
+    pub fn enabled(&self) -> bool {
+        self.landlock.as_ref().is_some_and(|i| *i)
+            || self.no_new_privs.as_ref().is_some_and(|i| *i)
+            || self.process_group.as_ref().is_some_and(|i| *i)
+            || self.memory_limit_mb.is_some()
+            || self.open_files_limit.is_some()
+            || self.user.is_some()
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Addresses {
     // Frontend host (MUST not contain http/https prefix, with no ending /)
@@ -244,10 +405,30 @@ pub struct Addresses {
     // Can contain host addresses as well
     //- eg: 127.0.0.1:8081, myaddr.com:8081
     pub backend_ssl_proxy: Option<String>,
-    // must be PEM format
+    // Serve the backend under this path on the frontend instead (MUST start with /, with no ending /)
+    // The api then shares the frontend's origin and certificate, so `backend_ssl_proxy` isn't needed
+    //- eg: /api
+    pub backend_path: Option<String>,
+    // Serve the frontend, backend (under `backend_path`, or /api by default) and proxy
+    // all from the frontend address, so only one port is needed
+    pub single_port: Option<bool>,
+    // If a port is already in use, pick a free one instead of failing to start.
+    // Whatever is picked is printed at startup, and isn't saved to the config
+    pub auto_ports: Option<bool>,
+    // Certificate chain and private key shared by all ssl listeners
+    // Relative paths are relative to the executable
+    // must be PEM format. Keys can be RSA, PKCS#8 or EC
     pub ssl_cert: Option<String>,
     // must be PEM format
     pub ssl_key: Option<String>,
+    // Optional certificate and key for each listener, in case they're on different hosts
+    // Any not set fall back to ssl_cert and ssl_key
+    pub frontend_ssl_cert: Option<String>,
+    pub frontend_ssl_key: Option<String>,
+    pub backend_ssl_cert: Option<String>,
+    pub backend_ssl_key: Option<String>,
+    pub proxy_ssl_cert: Option<String>,
+    pub proxy_ssl_key: Option<String>,
 }
 
 impl Default for Addresses {
@@ -260,11 +441,30 @@ impl Default for Addresses {
             use_ssl: None,
             ssl_cert: None,
             ssl_key: None,
+            frontend_ssl_cert: None,
+            frontend_ssl_key: None,
+            backend_ssl_cert: None,
+            backend_ssl_key: None,
+            proxy_ssl_cert: None,
+            proxy_ssl_key: None,
             backend_ssl_proxy: None,
+            backend_path: None,
+            single_port: None,
+            auto_ports: None,
         }
     }
 }
 
+// strips the port from an address like `myaddr.com:8080` or `[::1]:8080`
+pub fn host_part(addr: &str) -> &str {
+    let host = match addr.rsplit_once(':') {
+        Some((host, port)) if port.parse::<u16>().is_ok() => host,
+        _ => addr,
+    };
+
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
 impl Addresses {
     fn http_part(&self) -> &'static str {
         if self.use_ssl.as_ref().is_some_and(|b| *b) {
@@ -278,15 +478,286 @@ impl Addresses {
         format!("{}{}", self.http_part(), self.frontend)
     }
 
+    /// The port the backend listens on (on all interfaces)
+    pub fn backend_port(&self) -> anyhow::Result<u16> {
+        self.backend
+            .rsplit(':')
+            .next()
+            .map(|l| l.parse::<u16>().unwrap_or(80))
+            .ok_or(anyhow!("http part missing"))
+    }
+
     pub fn backend_uri(&self) -> String {
         format!("http://{}", self.backend)
     }
 
     pub fn proxy_uri(&self) -> String {
-        format!("{}{}", self.http_part(), self.proxy)
+        if self.single_port.as_ref().is_some_and(|i| *i) {
+            // proxy routes are served from the frontend
+            self.frontend_uri()
+        } else {
+            format!("{}{}", self.http_part(), self.proxy)
+        }
+    }
+
+    /// Host names (without port) of all the listeners served over ssl
+    pub fn ssl_hosts(&self) -> Vec<&str> {
+        let mut hosts = vec![host_part(&self.frontend)];
+
+        if !self.single_port.as_ref().is_some_and(|i| *i) {
+            hosts.push(host_part(&self.proxy));
+        }
+
+        if self.backend_prefix().is_none() {
+            if let Some(proxy) = &self.backend_ssl_proxy {
+                hosts.push(host_part(proxy));
+            }
+        }
+
+        hosts.sort_unstable();
+        hosts.dedup();
+        hosts
     }
 
     pub fn backend_ssl_proxy_uri(&self) -> Option<String> {
         Some(format!("https://{}", self.backend_ssl_proxy.as_ref()?))
     }
+
+    /// Catches addresses which would otherwise only fail once the servers start
+    fn validate(&self) -> anyhow::Result<()> {
+        if let Some(path) = &self.backend_path {
+            if !path.starts_with('/') {
+                return Err(anyhow!(
+                    "addresses.backend_path must start with /, eg: /{path}"
+                ));
+            }
+
+            if path.trim_end_matches('/').is_empty() {
+                return Err(anyhow!(
+                    "addresses.backend_path can't be /, the frontend is served there"
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The path the backend is mounted under on the frontend, if any
+    pub fn backend_prefix(&self) -> Option<&str> {
+        let path = if self.single_port.as_ref().is_some_and(|i| *i) {
+            Some(self.backend_path.as_deref().unwrap_or("/api"))
+        } else {
+            self.backend_path.as_deref()
+        };
+
+        path.map(|p| p.trim_end_matches('/'))
+            .filter(|p| !p.is_empty())
+    }
+
+    /// The public address clients use to reach the api through this instance.
+    /// See `Config::api_uri`, which also accounts for a remote backend
+    pub fn api_uri(&self) -> String {
+        if let Some(prefix) = self.backend_prefix() {
+            format!("{}{prefix}", self.frontend_uri())
+        } else if self.use_ssl.as_ref().is_some_and(|i| *i) {
+            // must use an ssl proxy since backend is always http
+            // checked at config load
+            self.backend_ssl_proxy_uri()
+                .expect("backend_ssl_proxy or backend_path is required when using ssl")
+        } else {
+            self.backend_uri()
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Frontend {
+    // Directory of files served instead of the embedded ones, eg: a custom logo or a hotfixed bundle
+    // Uses the same paths as the dist, and gets patched the same way
+    //- eg: overrides (with overrides/favicon.ico, overrides/assets/index-1234.js, ...)
+    pub override_dir: Option<String>,
+    // Load the whole frontend from this dist directory instead of the embedded one,
+    // so it can be upgraded without rebuilding. override_dir still applies on top
+    // Required when built without the embed-frontend feature
+    pub dist_dir: Option<String>,
+    // Name of this instance in the frontend's instance list
+    pub instance_name: Option<String>,
+    // Other instances to list alongside this one. The public instance list is never fetched
+    //- eg: [{ name = "Other", api_url = "https://api.other.com" }]
+    pub extra_instances: Option<Vec<Instance>>,
+    // Extra find and replace rules for the frontend files, run after the backend address is patched in
+    #[serde(default)]
+    pub rewrites: Vec<Rewrite>,
+    #[serde(default)]
+    pub headers: Headers,
+    #[serde(default)]
+    pub defaults: Defaults,
+}
+
+// Default preferences for new users of the frontend. Seeded into the browser's local storage
+// on first visit, so they don't override anything a user already changed
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Defaults {
+    // Api the frontend uses. This instance's backend by default
+    pub instance: Option<String>,
+    // Default video quality
+    //- eg: 1080
+    pub quality: Option<u32>,
+    // Region for trending videos
+    //- eg: US
+    pub region: Option<String>,
+    // dark, light or auto
+    pub theme: Option<String>,
+    pub sponsorblock: Option<bool>,
+    // SponsorBlock categories to skip
+    //- eg: ["sponsor", "selfpromo", "interaction"]
+    pub sponsorblock_categories: Option<Vec<String>>,
+    // Any other local storage keys to seed, eg: { listen = "true" }
+    pub extra: Option<BTreeMap<String, String>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Instance {
+    pub name: String,
+    pub api_url: String,
+}
+
+// eg:
+// [[frontend.rewrites]]
+// from = "Piped"
+// to = "My Tube"
+// files = ["index.html", "manifest.webmanifest"]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Rewrite {
+    // Text to replace
+    pub from: String,
+    // What to replace it with. Regex rules can use capture groups, eg: $1
+    pub to: String,
+    // Treat `from` as a regex instead of literal text
+    #[serde(default)]
+    pub regex: bool,
+    // Only rewrite files matching one of these globs (paths are relative to the dist)
+    // All text files by default
+    //- eg: ["assets/*.js", "index.html"]
+    pub files: Option<Vec<String>>,
+}
+
+impl Rewrite {
+    // a `from` matching nothing would insert `to` between every character of every file
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.from.is_empty() {
+            return Err(anyhow!("frontend.rewrites: from can't be empty"));
+        }
+
+        if self.regex && Regex::new(&self.from)?.is_match("") {
+            return Err(anyhow!(
+                "frontend.rewrites: regex {} can match empty text",
+                self.from
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+// Security headers sent with every frontend response
+// Leave one out to use the default, or set it to "" to not send it at all
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Headers {
+    // By default only allows this origin, plus the backend and proxy addresses, and the default
+    // and extra instances (with any https origin for their proxies)
+    // The hash of the injected default preferences script is added to a custom one's script-src
+    // (or a script-src copied from its default-src), so the defaults still apply
+    pub content_security_policy: Option<String>,
+    // Who may embed the frontend in a frame, added to the content security policy.
+    // Also added to a custom content_security_policy, unless it has its own frame-ancestors
+    //- eg: 'self' https://myaddr.com
+    pub frame_ancestors: Option<String>,
+    // Only sent when use_ssl is on
+    pub strict_transport_security: Option<String>,
+    pub x_content_type_options: Option<String>,
+    pub referrer_policy: Option<String>,
+    pub permissions_policy: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Challenge {
+    #[default]
+    #[serde(rename = "http-01")]
+    Http01,
+    #[serde(rename = "tls-alpn-01")]
+    TlsAlpn01,
+}
+
+// every field is optional, eg: just `enabled` and `contact`
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Acme {
+    // Get and renew certificates automatically for the frontend, backend ssl proxy and proxy hosts
+    // Requires use_ssl. When enabled, ssl_cert and ssl_key are ignored
+    pub enabled: bool,
+    // Contact addresses for the account
+    //- eg: ["mailto:admin@myaddr.com"]
+    pub contact: Vec<String>,
+    // Directory of the ACME server, Let's Encrypt by default
+    //- staging: https://acme-staging-v02.api.letsencrypt.org/directory
+    //- local pebble: https://localhost:14000/dir
+    pub directory_url: Option<String>,
+    // Extra root certificate to trust for the ACME server, eg: pebble's minica cert (PEM format)
+    pub directory_ca_cert: Option<String>,
+    // Challenge type, "http-01" or "tls-alpn-01"
+    // tls-alpn-01 is answered on the ssl listeners, so those must be reachable on port 443
+    pub challenge: Challenge,
+    // Address to answer http-01 challenges on (must be reachable on port 80)
+    //- eg: 0.0.0.0:80
+    pub http_challenge_address: Option<String>,
+    // Extra domains to add to the certificate
+    pub domains: Option<Vec<String>>,
+}
+
+impl Acme {
+    pub fn directory_url(&self) -> &str {
+        self.directory_url
+            .as_deref()
+            .unwrap_or("https://acme-v02.api.letsencrypt.org/directory")
+    }
+
+    pub fn http_challenge_address(&self) -> &str {
+        self.http_challenge_address
+            .as_deref()
+            .unwrap_or("0.0.0.0:80")
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Supervisor {
+    // What to do when the frontend, proxy, backend (or acme) stops or fails
+    // "shutdown" stops everything, "restart" starts just that part again.
+    // An in process jvm can't be restarted, so it always shuts down
+    pub on_failure: OnFailure,
+    // How many times in a row each part may be restarted before shutting down anyway (5 by default)
+    // The count starts over once a part has been running for 10 minutes
+    pub max_restarts: Option<u32>,
+    // Seconds to wait before restarting (5 by default)
+    pub restart_delay: Option<u64>,
+}
+
+impl Supervisor {
+    pub fn max_restarts(&self) -> u32 {
+        self.max_restarts.unwrap_or(5)
+    }
+
+    pub fn restart_delay(&self) -> Duration {
+        Duration::from_secs(self.restart_delay.unwrap_or(5))
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OnFailure {
+    #[default]
+    #[serde(rename = "shutdown")]
+    Shutdown,
+    #[serde(rename = "restart")]
+    Restart,
 }
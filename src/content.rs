@@ -1,26 +1,191 @@
 use anyhow::anyhow;
 
 static CONTENT_TYPES: &[(&str, &str)] = &[
-    ("html", "text/html"),
-    ("htm", "text/html"),
-    ("js", "text/javascript"),
-    ("css", "text/css"),
+    // text
+    ("html", "text/html; charset=utf-8"),
+    ("htm", "text/html; charset=utf-8"),
+    ("js", "text/javascript; charset=utf-8"),
+    ("mjs", "text/javascript; charset=utf-8"),
+    ("cjs", "text/javascript; charset=utf-8"),
+    ("css", "text/css; charset=utf-8"),
+    ("txt", "text/plain; charset=utf-8"),
+    ("md", "text/markdown; charset=utf-8"),
+    ("csv", "text/csv; charset=utf-8"),
+    ("vtt", "text/vtt; charset=utf-8"),
+    ("srt", "text/plain; charset=utf-8"),
+    ("ics", "text/calendar; charset=utf-8"),
+    ("xml", "application/xml; charset=utf-8"),
+    ("xhtml", "application/xhtml+xml; charset=utf-8"),
+    ("rss", "application/rss+xml; charset=utf-8"),
+    ("atom", "application/atom+xml; charset=utf-8"),
+    ("json", "application/json; charset=utf-8"),
+    ("map", "application/json; charset=utf-8"),
+    ("jsonld", "application/ld+json; charset=utf-8"),
+    ("webmanifest", "application/manifest+json; charset=utf-8"),
+    ("svg", "image/svg+xml; charset=utf-8"),
+    // images
     ("ico", "image/vnd.microsoft.icon"),
-    ("txt", "text/plain"),
-    ("xml", "application/xml"),
-    ("webmanifest", "application/manifest+json"),
     ("png", "image/png"),
-    ("svg", "image/svg+xml"),
+    ("apng", "image/apng"),
     ("gif", "image/gif"),
-    ("map", "application/json"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("bmp", "image/bmp"),
+    ("tif", "image/tiff"),
+    ("tiff", "image/tiff"),
+    // fonts
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+    ("eot", "application/vnd.ms-fontobject"),
+    // audio and video
+    ("mp4", "video/mp4"),
+    ("m4v", "video/mp4"),
+    ("webm", "video/webm"),
+    ("ogv", "video/ogg"),
+    ("mp3", "audio/mpeg"),
+    ("m4a", "audio/mp4"),
+    ("ogg", "audio/ogg"),
+    ("oga", "audio/ogg"),
+    ("opus", "audio/opus"),
+    ("wav", "audio/wav"),
+    ("flac", "audio/flac"),
+    ("m3u8", "application/vnd.apple.mpegurl"),
+    ("mpd", "application/dash+xml"),
+    // other
+    ("wasm", "application/wasm"),
+    ("pdf", "application/pdf"),
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+];
+
+// https://mimesniff.spec.whatwg.org/#matching-a-mime-type-pattern
+static SIGNATURES: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"\x00\x00\x01\x00", "image/vnd.microsoft.icon"),
+    (b"BM", "image/bmp"),
+    (b"wOFF", "font/woff"),
+    (b"wOF2", "font/woff2"),
+    (b"OTTO", "font/otf"),
+    (b"\x00\x01\x00\x00", "font/ttf"),
+    (b"\x00asm", "application/wasm"),
+    (b"%PDF-", "application/pdf"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"\x1f\x8b\x08", "application/gzip"),
+    (b"\x1a\x45\xdf\xa3", "video/webm"),
+    (b"OggS\x00", "application/ogg"),
+    (b"ID3", "audio/mpeg"),
 ];
 
 pub fn get_content_type(ext: &str) -> anyhow::Result<&'static str> {
     for (_ext, _type) in CONTENT_TYPES {
-        if ext == *_ext {
+        if ext.eq_ignore_ascii_case(_ext) {
             return Ok(_type);
         }
     }
 
     Err(anyhow!("Content type not found"))
 }
+
+/// Guesses the content type from the file contents, for files without a (known) extension
+pub fn sniff_content_type(contents: &[u8]) -> &'static str {
+    for (signature, _type) in SIGNATURES {
+        if contents.starts_with(signature) {
+            return _type;
+        }
+    }
+
+    // riff containers have the format after the size
+    if contents.len() >= 12 && contents.starts_with(b"RIFF") {
+        match &contents[8..12] {
+            b"WEBP" => return "image/webp",
+            b"WAVE" => return "audio/wav",
+            _ => (),
+        }
+    }
+
+    // iso media, eg: mp4 and avif
+    if contents.len() >= 12 && &contents[4..8] == b"ftyp" {
+        return match &contents[8..12] {
+            b"avif" | b"avis" => "image/avif",
+            _ => "video/mp4",
+        };
+    }
+
+    let Ok(text) = std::str::from_utf8(contents) else {
+        return "application/octet-stream";
+    };
+
+    let start = text
+        .trim_start_matches('\u{feff}')
+        .trim_start()
+        .get(..64)
+        .unwrap_or(text.trim_start())
+        .to_ascii_lowercase();
+
+    if start.starts_with("<!doctype html") || start.starts_with("<html") {
+        "text/html; charset=utf-8"
+    } else if start.starts_with("<svg") {
+        "image/svg+xml; charset=utf-8"
+    } else if start.starts_with("<?xml") {
+        "application/xml; charset=utf-8"
+    } else if (start.starts_with('{') || start.starts_with('['))
+        && serde_json::from_str::<serde::de::IgnoredAny>(text).is_ok()
+    {
+        "application/json; charset=utf-8"
+    } else {
+        "text/plain; charset=utf-8"
+    }
+}
+
+/// Content type of a file from its extension, falling back to sniffing the contents
+pub fn content_type(path: &str, contents: &[u8]) -> &'static str {
+    std::path::Path::new(path)
+        .extension()
+        .and_then(|ext| get_content_type(ext.to_str()?).ok())
+        .unwrap_or_else(|| sniff_content_type(contents))
+}
+
+#[cfg(all(test, feature = "embed-frontend"))]
+mod tests {
+    use include_dir::Dir;
+
+    use super::*;
+    use crate::PIPED_SRC;
+
+    fn check_dir(dir: &Dir) {
+        for file in dir.files() {
+            let path = file.path();
+
+            match path.extension() {
+                Some(ext) => assert!(
+                    get_content_type(ext.to_str().unwrap()).is_ok(),
+                    "{} has no content type for its extension",
+                    path.display()
+                ),
+
+                None => assert_ne!(
+                    sniff_content_type(file.contents()),
+                    "application/octet-stream",
+                    "{} couldn't be sniffed",
+                    path.display()
+                ),
+            }
+        }
+
+        for dir in dir.dirs() {
+            check_dir(dir);
+        }
+    }
+
+    #[test]
+    fn dist_has_no_fallback_types() {
+        check_dir(&PIPED_SRC);
+    }
+}
@@ -0,0 +1,46 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::Path,
+};
+
+use anyhow::anyhow;
+
+/// Writes to a temp file first and renames it over `path`, so `path` is never partially written.
+/// On unix the file is created with `mode`, eg: 0o600 for one only we can read
+pub fn write_atomic(path: &Path, data: &[u8], mode: Option<u32>) -> anyhow::Result<()> {
+    let dir = path.parent().ok_or(anyhow!("Failed to get parent"))?;
+    let file_name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or(anyhow!("failed to make string"))?;
+    let tmp_path = dir.join(format!("{file_name}.{}.tmp", std::process::id()));
+
+    let result = (|| {
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        if let Some(mode) = mode {
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, mode);
+        }
+        #[cfg(not(unix))]
+        let _ = mode;
+
+        let mut file = options.open(&tmp_path)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)
+    })();
+
+    if let Err(e) = result {
+        fs::remove_file(&tmp_path).ok();
+        return Err(anyhow!("Failed to write {}: {e}", path.display()));
+    }
+
+    // make the rename itself durable. Directories can't be opened on windows, which is fine there
+    if let Ok(dir) = fs::File::open(dir) {
+        dir.sync_all().ok();
+    }
+
+    Ok(())
+}
@@ -0,0 +1,182 @@
+use std::sync::OnceLock;
+
+use axum::{
+    http::{HeaderMap, HeaderName, HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+};
+
+use crate::{assets, config::Config};
+
+static HEADERS: OnceLock<HeaderMap> = OnceLock::new();
+
+/// Builds the security headers for the frontend from the config
+pub fn build_headers(config: &Config) -> anyhow::Result<()> {
+    let addresses = &config.addresses;
+    let headers = &config.frontend.headers;
+    let use_ssl = addresses.use_ssl.as_ref().is_some_and(|i| *i);
+
+    let custom_csp = headers
+        .content_security_policy
+        .as_ref()
+        .map(|csp| match &headers.frame_ancestors {
+            // an empty one means the header is disabled
+            Some(frame_ancestors) if !csp.is_empty() && !csp.contains("frame-ancestors") => {
+                format!(
+                    "{}; frame-ancestors {frame_ancestors}",
+                    csp.trim_end_matches([';', ' '])
+                )
+            }
+
+            _ => csp.clone(),
+        })
+        .map(|csp| match assets::inline_script_hash() {
+            Some(hash) if !csp.is_empty() => with_script_hash(&csp, hash),
+            _ => csp,
+        });
+
+    let csp = custom_csp.unwrap_or_else(|| {
+        // the api and proxy may be on other origins, and so may the other instances users can pick
+        let frontend = &config.frontend;
+        let (own_frontend, own_api) =
+            (origin(&addresses.frontend_uri()), origin(&config.api_uri()));
+        let extra_apis = frontend
+            .extra_instances
+            .iter()
+            .flatten()
+            .map(|i| &i.api_url);
+        let instances = frontend
+            .defaults
+            .instance
+            .iter()
+            .chain(extra_apis)
+            .map(|uri| origin(uri))
+            .filter(|o| *o != own_frontend && *o != own_api)
+            .collect::<Vec<_>>();
+
+        let mut origins = vec![own_api, origin(&addresses.proxy_uri())];
+        origins.extend(instances.iter().cloned());
+        origins.retain(|o| *o != own_frontend);
+        origins.sort();
+        origins.dedup();
+        // a remote backend or another instance sends media through its own proxy,
+        // which we don't know the address of
+        if config.remote_api().is_some() || !instances.is_empty() {
+            origins.push("https:".to_string());
+        }
+        let origins = origins.join(" ");
+
+        let frame_ancestors = headers.frame_ancestors.as_deref().unwrap_or("'none'");
+
+        [
+            "default-src 'self'".to_string(),
+            // the injected default preferences are allowed by their hash
+            format!(
+                "script-src 'self' {}",
+                assets::inline_script_hash().unwrap_or_default()
+            ),
+            "style-src 'self' 'unsafe-inline'".to_string(),
+            format!("img-src 'self' data: blob: {origins}"),
+            // videos are played through media source extensions
+            format!("media-src 'self' blob: {origins}"),
+            format!("connect-src 'self' {origins}"),
+            "font-src 'self' data:".to_string(),
+            "worker-src 'self' blob:".to_string(),
+            "manifest-src 'self'".to_string(),
+            "object-src 'none'".to_string(),
+            "base-uri 'self'".to_string(),
+            format!("frame-ancestors {frame_ancestors}"),
+        ]
+        .join("; ")
+    });
+
+    let hsts = if use_ssl {
+        headers
+            .strict_transport_security
+            .as_deref()
+            .unwrap_or("max-age=63072000; includeSubDomains")
+    } else {
+        ""
+    };
+
+    let defaults = [
+        ("content-security-policy", csp.as_str()),
+        ("strict-transport-security", hsts),
+        (
+            "x-content-type-options",
+            headers
+                .x_content_type_options
+                .as_deref()
+                .unwrap_or("nosniff"),
+        ),
+        (
+            "referrer-policy",
+            headers.referrer_policy.as_deref().unwrap_or("no-referrer"),
+        ),
+        (
+            "permissions-policy",
+            headers.permissions_policy.as_deref().unwrap_or(
+                "camera=(), microphone=(), geolocation=(), payment=(), usb=(), interest-cohort=()",
+            ),
+        ),
+    ];
+
+    let mut map = HeaderMap::new();
+    for (name, value) in defaults {
+        // empty means disabled
+        if !value.is_empty() {
+            map.insert(HeaderName::from_static(name), HeaderValue::from_str(value)?);
+        }
+    }
+
+    HEADERS
+        .set(map)
+        .map_err(|_| anyhow::anyhow!("headers already built"))?;
+
+    Ok(())
+}
+
+// allows the injected defaults script in a custom csp, through script-src, or a script-src
+// copied from default-src. Neither means scripts aren't restricted, so nothing is added
+fn with_script_hash(csp: &str, hash: &str) -> String {
+    let directives = csp
+        .split(';')
+        .map(str::trim)
+        .filter(|d| !d.is_empty())
+        .collect::<Vec<_>>();
+    let find = |name: &str| {
+        directives
+            .iter()
+            .position(|d| d.split_whitespace().next() == Some(name))
+    };
+
+    let mut directives = directives.iter().map(|d| d.to_string()).collect::<Vec<_>>();
+    if let Some(i) = find("script-src") {
+        directives[i] = format!("{} {hash}", directives[i]);
+    } else if let Some(i) = find("default-src") {
+        let sources = directives[i].trim_start_matches("default-src");
+        directives.push(format!("script-src{sources} {hash}"));
+    }
+
+    directives.join("; ")
+}
+
+// scheme://host:port of a uri, without any path
+fn origin(uri: &str) -> String {
+    let (scheme, rest) = uri.split_once("://").unwrap_or(("http", uri));
+    let host = rest.split('/').next().unwrap_or(rest);
+    format!("{scheme}://{host}")
+}
+
+/// Middleware adding the security headers to every frontend response
+pub async fn security_headers<B>(request: Request<B>, next: Next<B>) -> Response {
+    let mut response = next.run(request).await;
+
+    for (name, value) in HEADERS.get().unwrap() {
+        if !response.headers().contains_key(name) {
+            response.headers_mut().insert(name, value.clone());
+        }
+    }
+
+    response
+}
@@ -0,0 +1,194 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io,
+    path::{Path, PathBuf},
+    process, thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use directories::ProjectDirs;
+use fs2::FileExt;
+
+// held locked for as long as an instance runs. The pid is in a separate file, since windows
+// doesn't allow reading a locked file
+const LOCK_FILE: &str = "instance.lock";
+const PID_FILE: &str = "instance.pid";
+
+// how long `stop` waits for the instance to exit. Longer than it gives the backend
+const STOP_TIMEOUT: Duration = Duration::from_secs(45);
+
+/// Makes sure only one instance runs at a time. Released when dropped
+#[derive(Debug)]
+pub struct InstanceLock {
+    _lock: File,
+    pid_file: PathBuf,
+    extra_pid_file: Option<PathBuf>,
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        fs::remove_file(&self.pid_file).ok();
+
+        if let Some(pid_file) = &self.extra_pid_file {
+            fs::remove_file(pid_file).ok();
+        }
+    }
+}
+
+fn data_dir() -> anyhow::Result<PathBuf> {
+    let project_dir = ProjectDirs::from("", "", "youtube-server")
+        .ok_or(anyhow!("Failed to get project directory"))?;
+
+    let data_local = project_dir.data_local_dir();
+    fs::create_dir_all(data_local)?;
+
+    Ok(data_local.to_path_buf())
+}
+
+fn open_lock(data_local: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(data_local.join(LOCK_FILE))
+}
+
+/// The pid of the running instance, if there is one
+fn running_pid(data_local: &Path) -> anyhow::Result<Option<u32>> {
+    let lock = open_lock(data_local)?;
+
+    if FileExt::try_lock_exclusive(&lock).is_ok() {
+        FileExt::unlock(&lock)?;
+        return Ok(None);
+    }
+
+    // it may not have written its pid just yet
+    let pid = fs::read_to_string(data_local.join(PID_FILE))
+        .ok()
+        .and_then(|pid| pid.trim().parse().ok());
+
+    Ok(Some(pid.unwrap_or_default()))
+}
+
+/// Takes the instance lock, failing if another instance already has it.
+/// The pid is also written to `pid_file` if given
+pub fn lock(pid_file: Option<PathBuf>) -> anyhow::Result<InstanceLock> {
+    let data_local = data_dir()?;
+    let lock = open_lock(&data_local)?;
+
+    if FileExt::try_lock_exclusive(&lock).is_err() {
+        let pid = fs::read_to_string(data_local.join(PID_FILE)).unwrap_or_default();
+        return Err(anyhow!(
+            "youtube-server is already running (pid {}). Stop it first with `youtube-server stop`",
+            pid.trim()
+        ));
+    }
+
+    let pid = process::id().to_string();
+    let own_pid_file = data_local.join(PID_FILE);
+    fs::write(&own_pid_file, &pid)?;
+
+    if let Some(pid_file) = &pid_file {
+        fs::write(pid_file, &pid)?;
+    }
+
+    Ok(InstanceLock {
+        _lock: lock,
+        pid_file: own_pid_file,
+        extra_pid_file: pid_file,
+    })
+}
+
+/// Prints whether an instance is running. Returns false if not
+pub fn status() -> anyhow::Result<bool> {
+    match running_pid(&data_dir()?)? {
+        Some(pid) => {
+            println!("youtube-server is running (pid {pid})");
+            Ok(true)
+        }
+
+        None => {
+            println!("youtube-server is not running");
+            Ok(false)
+        }
+    }
+}
+
+/// Asks the running instance to shut down, and waits for it to
+pub fn stop() -> anyhow::Result<()> {
+    let data_local = data_dir()?;
+
+    let Some(pid) = running_pid(&data_local)? else {
+        println!("youtube-server is not running");
+        return Ok(());
+    };
+
+    if pid == 0 {
+        return Err(anyhow!("youtube-server is running, but its pid is unknown"));
+    }
+
+    terminate(pid)?;
+
+    let started = Instant::now();
+    while running_pid(&data_local)?.is_some() {
+        if started.elapsed() > STOP_TIMEOUT {
+            return Err(anyhow!(
+                "youtube-server (pid {pid}) didn't stop in {STOP_TIMEOUT:?}"
+            ));
+        }
+
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    println!("Stopped youtube-server (pid {pid})");
+
+    Ok(())
+}
+
+/// Asks a process to exit
+#[cfg(unix)]
+pub fn terminate(pid: u32) -> io::Result<()> {
+    if unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Asks a process to exit
+#[cfg(windows)]
+pub fn terminate(pid: u32) -> io::Result<()> {
+    // console programs can't be asked nicely, and this takes the backend down with it
+    let status = process::Command::new("taskkill")
+        .args(["/PID", &pid.to_string(), "/T", "/F"])
+        .status()?;
+
+    if !status.success() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("taskkill failed with {status}"),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Waits for ctrl+c, or on unix a SIGTERM (which `stop` sends)
+pub async fn shutdown_signal() -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate())?;
+
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result,
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await
+}
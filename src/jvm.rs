@@ -0,0 +1,169 @@
+use std::{
+    ffi::{c_void, CString},
+    path::{Path, PathBuf},
+    ptr,
+    thread::{self, JoinHandle},
+};
+
+use anyhow::anyhow;
+use jni::{
+    objects::{JObject, JString, JValue},
+    sys::{self, jint, JavaVMInitArgs, JavaVMOption, JNI_OK, JNI_VERSION_1_8},
+    JNIEnv, JavaVM,
+};
+use libloading::Library;
+
+type CreateJavaVm =
+    unsafe extern "system" fn(*mut *mut sys::JavaVM, *mut *mut c_void, *mut c_void) -> jint;
+
+/// Starts the jvm inside this process from the jre in `jre_dir`, and runs the jar's main class.
+/// The thread finishes when the backend does. Since it shares our process, a `System.exit` in
+/// the backend also exits this program
+pub fn start(
+    jre_dir: &Path,
+    jar_path: &str,
+    options: Vec<String>,
+) -> anyhow::Result<JoinHandle<anyhow::Result<()>>> {
+    let libjvm = libjvm_path(jre_dir);
+    if !libjvm.exists() {
+        return Err(anyhow!("libjvm not found at {}", libjvm.display()));
+    }
+
+    let mut options = options;
+    options.push(format!("-Djava.class.path={jar_path}"));
+    // otherwise the jvm handles ctrl+c and SIGTERM itself with a System.exit, skipping our own
+    // shutdown (and the instance lock and pid file cleanup)
+    options.push("-Xrs".to_string());
+    let jar_path = jar_path.to_string();
+
+    let handle = thread::Builder::new()
+        .name("jvm".to_string())
+        .spawn(move || {
+            let vm = create_vm(&libjvm, &options)?;
+
+            {
+                let mut env = vm.attach_current_thread_permanently()?;
+
+                if let Err(e) = run_main(&mut env, &jar_path) {
+                    if env.exception_check()? {
+                        env.exception_describe()?;
+                    }
+
+                    return Err(e);
+                }
+            }
+
+            // main returns once the server is started. Destroying waits for all its (non daemon)
+            // threads to finish, same as the java launcher does. It re-attaches this thread,
+            // so detach first or jni would try detaching it again after the vm is gone
+            unsafe {
+                vm.detach_current_thread();
+                vm.destroy()?;
+            }
+
+            Ok(())
+        })?;
+
+    Ok(handle)
+}
+
+// eg: jre/lib/server/libjvm.so
+fn libjvm_path(jre_dir: &Path) -> PathBuf {
+    if cfg!(target_os = "windows") {
+        jre_dir.join("bin").join("server").join("jvm.dll")
+    } else if cfg!(target_os = "macos") {
+        jre_dir.join("lib").join("server").join("libjvm.dylib")
+    } else {
+        jre_dir.join("lib").join("server").join("libjvm.so")
+    }
+}
+
+fn create_vm(libjvm: &Path, options: &[String]) -> anyhow::Result<JavaVM> {
+    // a jvm can't be unloaded, so the library has to live forever anyway
+    let library = unsafe { Library::new(libjvm)? };
+    let library: &'static Library = Box::leak(Box::new(library));
+    let create: libloading::Symbol<CreateJavaVm> = unsafe { library.get(b"JNI_CreateJavaVM\0")? };
+
+    // the option strings must outlive the call
+    let option_strings = options
+        .iter()
+        .map(|o| CString::new(o.as_str()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut vm_options = option_strings
+        .iter()
+        .map(|o| JavaVMOption {
+            optionString: o.as_ptr() as *mut _,
+            extraInfo: ptr::null_mut(),
+        })
+        .collect::<Vec<_>>();
+
+    let mut args = JavaVMInitArgs {
+        version: JNI_VERSION_1_8,
+        nOptions: vm_options.len() as jint,
+        options: vm_options.as_mut_ptr(),
+        ignoreUnrecognized: sys::JNI_FALSE,
+    };
+
+    let mut vm = ptr::null_mut();
+    let mut env = ptr::null_mut();
+    let result = unsafe { create(&mut vm, &mut env, &mut args as *mut _ as *mut c_void) };
+
+    if result != JNI_OK {
+        return Err(anyhow!("JNI_CreateJavaVM failed with {result}"));
+    }
+
+    Ok(unsafe { JavaVM::from_raw(vm)? })
+}
+
+/// Calls `main` of the class in the jar's manifest, like `java -jar` does
+fn run_main(env: &mut JNIEnv, jar_path: &str) -> anyhow::Result<()> {
+    let jar_path = env.new_string(jar_path)?;
+    let jar = env.new_object(
+        "java/util/jar/JarFile",
+        "(Ljava/lang/String;)V",
+        &[JValue::Object(&jar_path)],
+    )?;
+
+    let manifest = env
+        .call_method(&jar, "getManifest", "()Ljava/util/jar/Manifest;", &[])?
+        .l()?;
+    if manifest.is_null() {
+        return Err(anyhow!("backend jar has no manifest"));
+    }
+
+    let attributes = env
+        .call_method(
+            &manifest,
+            "getMainAttributes",
+            "()Ljava/util/jar/Attributes;",
+            &[],
+        )?
+        .l()?;
+    let key = env.new_string("Main-Class")?;
+    let main_class = env
+        .call_method(
+            &attributes,
+            "getValue",
+            "(Ljava/lang/String;)Ljava/lang/String;",
+            &[JValue::Object(&key)],
+        )?
+        .l()?;
+    if main_class.is_null() {
+        return Err(anyhow!("backend jar has no Main-Class"));
+    }
+
+    let main_class: String = env.get_string(&JString::from(main_class))?.into();
+    env.call_method(&jar, "close", "()V", &[])?;
+
+    let class = env.find_class(main_class.replace('.', "/"))?;
+    let args = env.new_object_array(0, "java/lang/String", JObject::null())?;
+
+    env.call_static_method(
+        class,
+        "main",
+        "([Ljava/lang/String;)V",
+        &[JValue::Object(&args)],
+    )?;
+
+    Ok(())
+}
@@ -1,95 +1,199 @@
+mod acme;
 mod assets;
 mod backend;
+mod cache;
+mod cds;
+mod compress;
 mod config;
 mod content;
+mod files;
+mod headers;
+mod instance;
+#[cfg(feature = "in-process-jvm")]
+mod jvm;
+mod ports;
 mod proxy;
 mod resolver;
+mod sandbox;
+mod setup;
+mod tasks;
+mod tls;
 
 // include generated hash file
+#[cfg(feature = "embed-backend")]
 include!(concat!(env!("OUT_DIR"), "/hash.rs"));
 
-use std::{env, path::Path as StdPath, sync::Arc};
+use std::{
+    path::{Path as StdPath, PathBuf},
+    sync::Arc,
+};
 
 use axum::{
     extract::Path,
-    http::{header, HeaderName, StatusCode},
-    response::IntoResponse,
-    routing::get,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{any, get},
     Router,
 };
-use axum_server::tls_rustls::RustlsConfig;
+use clap::{Parser, Subcommand};
+use config::Config;
+#[cfg(feature = "embed-frontend")]
 use include_dir::{include_dir, Dir};
 
-use crate::content::get_content_type;
-
 // the entire website files
-static PIPED_SRC: Dir = include_dir!("$CARGO_MANIFEST_DIR/piped/dist");
+// (the paths are set by build.rs, they may point at prebuilt artifacts)
+#[cfg(feature = "embed-frontend")]
+static PIPED_SRC: Dir = include_dir!("$PIPED_DIST_DIR");
+
+#[cfg(feature = "embed-backend")]
+static PIPED_JAR: &[u8] = include_bytes!(env!("PIPED_JAR_PATH"));
+
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Args {
+    /// Also write the process id to this file while running
+    #[arg(long)]
+    pidfile: Option<PathBuf>,
 
-static PIPED_JAR: &[u8] = include_bytes!("../piped-backend/build/libs/piped-1.0-all.jar");
+    #[command(subcommand)]
+    command: Option<Cmd>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Cmd {
+    /// Show whether youtube-server is already running
+    Status,
+    /// Stop the running youtube-server
+    Stop,
+}
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let config = Arc::new(config::Config::get_config()?);
+    let args = Args::parse();
+
+    match args.command {
+        Some(Cmd::Status) => {
+            if !instance::status()? {
+                std::process::exit(1);
+            }
+
+            return Ok(());
+        }
+
+        Some(Cmd::Stop) => return instance::stop(),
+
+        None => (),
+    }
+
+    // a second instance would fight over the ports and the data dir
+    let _lock = instance::lock(args.pidfile)?;
+
+    // walk through making a config instead of starting with one that most likely doesn't work
+    if setup::is_first_run()? {
+        tokio::select! {
+            result = setup::run_setup() => result?,
+            result = instance::shutdown_signal() => return Ok(result?),
+        }
+    }
+
+    let mut config = Config::get_config()?;
+
+    // fail before anything starts if a port is taken, rather than leaving the backend running
+    ports::preflight(&mut config)?;
+    config.set_env()?;
+
+    let config = Arc::new(config);
 
     // build patched runtime assets for the frontend
-    assets::patch_assets(&config);
+    assets::patch_assets(&config)?;
+    cache::compute_etags();
+    // takes a few seconds, so done while everything else starts
+    tokio::task::spawn_blocking(compress::compress_assets);
+    headers::build_headers(&config)?;
+
+    // everything long running, so a part failing takes down (or restarts) just what it should
+    let mut tasks = tasks::Tasks::new();
+
+    // certificates need to be ready before any ssl listener starts
+    if config.addresses.use_ssl.as_ref().is_some_and(|i| *i) && config.acme.enabled {
+        acme::start(config.clone(), &mut tasks)?;
+    }
 
     // start backend, but keep it open as long as the frontend is open for
-    backend::run_backend(config.clone())?;
+    backend::run_backend(config.clone(), &mut tasks)?;
 
-    // frontend
     let config2 = config.clone();
-    tokio::spawn(async move {
-        // index.html @ /
-        let app = Router::new()
-            .route("/", get(get_index))
-            .route("/*file", get(get_file));
-
-        let frontend_addr = resolver::get_addresses(&config2.addresses.frontend)
-            .expect("Failed to resolve frontend address");
-        #[allow(clippy::if_same_then_else)]
-        let frontend_addr = if config2.addresses.use_ipv6.as_ref().is_some_and(|i| *i) {
-            frontend_addr.ipv6.as_ref()
-        } else if frontend_addr.ipv6.as_ref().is_some() {
-            frontend_addr.ipv6.as_ref()
-        } else {
-            frontend_addr.ipv4.as_ref()
-        }
-        .expect("Failed to resolve frontend address");
+    tasks.spawn("frontend", move || serve_frontend(config2.clone()));
 
-        if config2.addresses.use_ssl.as_ref().is_some_and(|i| *i) {
-            let exe_path = env::current_exe().unwrap();
-            let exe_path = exe_path.parent().unwrap();
+    // in single port mode the proxy is served from the frontend instead of its own listener
+    if !config.addresses.single_port.as_ref().is_some_and(|i| *i) {
+        let config2 = config.clone();
+        tasks.spawn("proxy", move || {
+            let config = config2.clone();
+            async move { proxy::start_proxy(&config).await }
+        });
+    }
 
-            let config = RustlsConfig::from_pem_file(
-                exe_path.join(config2.addresses.ssl_cert.as_ref().unwrap()),
-                exe_path.join(config2.addresses.ssl_key.as_ref().unwrap()),
-            )
-            .await
-            .unwrap();
+    let result = tokio::select! {
+        error = tasks.supervise(&config.supervisor) => Err(error),
+        result = instance::shutdown_signal() => result.map_err(Into::into),
+    };
 
-            axum_server::bind_rustls(*frontend_addr, config)
-                .serve(app.into_make_service())
-                .await
-                .unwrap();
-        } else {
-            axum::Server::bind(frontend_addr)
-                .serve(app.into_make_service())
-                .await
-                .unwrap();
-        }
-    });
+    // the backend has to be gone before the instance lock is released
+    println!("Shutting down");
+    tokio::task::spawn_blocking(backend::stop).await?;
 
-    // proxy
-    proxy::start_proxy(&config).await?;
+    result
+}
+
+async fn serve_frontend(config: Arc<Config>) -> anyhow::Result<()> {
+    // index.html @ /
+    let app = Router::new()
+        .route("/", get(get_index))
+        .route("/*file", get(get_file));
+
+    // serve the backend on the same origin, so it shares the frontend's address and certificate
+    let app = if let Some(prefix) = config.addresses.backend_prefix() {
+        app.nest_service(prefix, tower::service_fn(backend::backend_proxy))
+    } else {
+        app
+    };
+
+    let app = if config.addresses.single_port.as_ref().is_some_and(|i| *i) {
+        proxy::PROXY_ROUTES
+            .iter()
+            .fold(app, |app, path| app.route(path, any(proxy::index)))
+    } else {
+        app
+    };
+
+    let app = app.layer(middleware::from_fn(headers::security_headers));
+
+    let frontend_addr = ports::listen_addr(
+        &config.addresses.frontend,
+        config.addresses.use_ipv6.as_ref().is_some_and(|i| *i),
+    )?;
+
+    if config.addresses.use_ssl.as_ref().is_some_and(|i| *i) {
+        let rustls_config = tls::rustls_config(&config, tls::Listener::Frontend).await?;
+
+        axum_server::bind_rustls(frontend_addr, rustls_config)
+            .serve(app.into_make_service())
+            .await?;
+    } else {
+        axum::Server::try_bind(&frontend_addr)?
+            .serve(app.into_make_service())
+            .await?;
+    }
 
     Ok(())
 }
 
-fn _get_index_internal() -> (StatusCode, [(HeaderName, &'static str); 1], &'static [u8]) {
+fn _get_index_internal(request: &HeaderMap) -> Response {
     let (status, content, content_type) = {
-        if let Some(file) = PIPED_SRC.get_file("index.html") {
-            let is_binary = file.contents_utf8().is_none();
+        if let Some(file) = assets::get_file("index.html") {
+            let is_binary = std::str::from_utf8(file).is_err();
 
             if is_binary {
                 (
@@ -98,7 +202,7 @@ fn _get_index_internal() -> (StatusCode, [(HeaderName, &'static str); 1], &'stat
                     "text/plain",
                 )
             } else {
-                (StatusCode::OK, file.contents(), "text/html")
+                return file_response("index.html", file, "text/html; charset=utf-8", request);
             }
         } else {
             (
@@ -109,43 +213,79 @@ fn _get_index_internal() -> (StatusCode, [(HeaderName, &'static str); 1], &'stat
         }
     };
 
-    (status, [(header::CONTENT_TYPE, content_type)], content)
+    (status, [(header::CONTENT_TYPE, content_type)], content).into_response()
 }
 
-async fn get_index() -> impl IntoResponse {
-    _get_index_internal()
+async fn get_index(headers: HeaderMap) -> Response {
+    _get_index_internal(&headers)
 }
 
-async fn get_file(Path(path): Path<String>) -> impl IntoResponse {
-    let (status, content, is_binary) = {
-        if let Some(asset) = assets::get_patched_asset(&path) {
-            // these are only strings due to how they were patched, so just default ot false
-            (StatusCode::OK, asset.as_bytes(), false)
-        } else if let Some(file) = PIPED_SRC.get_file(&path) {
-            (
-                StatusCode::OK,
-                file.contents(),
-                file.contents_utf8().is_none(),
-            )
-        } else {
+async fn get_file(Path(path): Path<String>, headers: HeaderMap) -> Response {
+    let content = {
+        if let Some(file) = assets::get_file(&path) {
+            file
+        } else if is_navigation(&path, &headers) {
             // It's best to just return the index page if not found and let everything else be handled
-            return _get_index_internal();
+            return _get_index_internal(&headers);
+        } else {
+            // a missing asset shouldn't get the index page, or it ends up cached as the asset
+            return (
+                StatusCode::NOT_FOUND,
+                [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+                "404 Not Found",
+            )
+                .into_response();
         }
     };
 
-    let content_type = if let Some(ext) = StdPath::new(&path).extension() {
-        get_content_type(ext.to_str().unwrap_or_default()).unwrap_or({
-            if is_binary {
-                "application/octet-stream"
-            } else {
-                "text/plain"
-            }
-        })
-    } else if is_binary {
-        "application/octet-stream"
-    } else {
-        "text/plain"
+    let content_type = content::content_type(&path, content);
+
+    file_response(&path, content, content_type, &headers)
+}
+
+/// Whether a request for a missing file is the browser navigating to an spa route
+/// (like `/watch`), rather than requesting an asset
+fn is_navigation(path: &str, request: &HeaderMap) -> bool {
+    let accepts_html = request
+        .get(header::ACCEPT)
+        .and_then(|h| h.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/html"));
+
+    let has_extension = StdPath::new(path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.contains('.'));
+
+    accepts_html || !has_extension
+}
+
+/// Responds with a file (precompressed if the client accepts it),
+/// or 304 if the client's cached copy is still fresh
+fn file_response(
+    path: &str,
+    content: &'static [u8],
+    content_type: &'static str,
+    request: &HeaderMap,
+) -> Response {
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+
+    let (encoding, content) = match compress::negotiate(path, request) {
+        Some((encoding, compressed)) => {
+            headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static(encoding));
+            (Some(encoding), compressed)
+        }
+
+        None => (None, content),
     };
 
-    (status, [(header::CONTENT_TYPE, content_type)], content)
+    if compress::is_compressed(path) {
+        headers.insert(header::VARY, HeaderValue::from_static("accept-encoding"));
+    }
+
+    if cache::is_fresh(path, encoding, content, request, &mut headers) {
+        return (StatusCode::NOT_MODIFIED, headers).into_response();
+    }
+
+    (StatusCode::OK, headers, content).into_response()
 }
@@ -0,0 +1,120 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener};
+
+use anyhow::anyhow;
+
+use crate::{
+    config::{self, Config},
+    resolver,
+};
+
+/// Test binds every listener this instance will open, so a busy port is reported before anything
+/// starts instead of failing from inside a running server. All conflicts are reported at once.
+/// With `auto_ports`, busy ports are swapped for free ones in the config instead
+pub fn preflight(config: &mut Config) -> anyhow::Result<()> {
+    let addresses = &mut config.addresses;
+
+    let use_ipv6 = addresses.use_ipv6.as_ref().is_some_and(|i| *i);
+    let auto_ports = addresses.auto_ports.as_ref().is_some_and(|i| *i);
+    let single_port = addresses.single_port.as_ref().is_some_and(|i| *i);
+    let use_ssl_proxy =
+        addresses.use_ssl.as_ref().is_some_and(|i| *i) && addresses.backend_prefix().is_none();
+    let run_backend = !config.backend.disable;
+    let backend_port = addresses.backend_port()?;
+
+    let mut listeners = vec![("frontend", &mut addresses.frontend)];
+    if run_backend {
+        listeners.push(("backend", &mut addresses.backend));
+    }
+    if !single_port {
+        listeners.push(("proxy", &mut addresses.proxy));
+    }
+    if let Some(ssl_proxy) = addresses
+        .backend_ssl_proxy
+        .as_mut()
+        .filter(|_| use_ssl_proxy)
+    {
+        listeners.push(("backend_ssl_proxy", ssl_proxy));
+    }
+
+    // kept bound until the end, so two listeners can't end up on the same port
+    let mut bound = Vec::new();
+    let mut conflicts = Vec::new();
+
+    for (name, addr) in listeners {
+        let socket_addr = if name == "backend" {
+            // the backend listens on all interfaces
+            Ok(SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                backend_port,
+            ))
+        } else {
+            listen_addr(addr, use_ipv6)
+        };
+
+        let socket_addr = match socket_addr {
+            Ok(socket_addr) => socket_addr,
+
+            Err(e) => {
+                conflicts.push(format!("{name} ({addr}): {e}"));
+                continue;
+            }
+        };
+
+        let error = match TcpListener::bind(socket_addr) {
+            Ok(listener) => {
+                bound.push(listener);
+                continue;
+            }
+
+            Err(e) => e,
+        };
+
+        if !auto_ports {
+            conflicts.push(format!("{name} ({addr} -> {socket_addr}): {error}"));
+            continue;
+        }
+
+        let listener = TcpListener::bind(SocketAddr::new(socket_addr.ip(), 0))?;
+        let port = listener.local_addr()?.port();
+        bound.push(listener);
+
+        let new_addr = with_port(addr, port);
+        println!("{name} address {addr} is unavailable ({error}), using {new_addr} instead");
+        *addr = new_addr;
+    }
+
+    if !conflicts.is_empty() {
+        return Err(anyhow!(
+            "Some listener addresses are unavailable:\n  {}\nFree up or change these ports, or set `auto_ports = true` under [addresses] to pick free ones",
+            conflicts.join("\n  ")
+        ));
+    }
+
+    Ok(())
+}
+
+/// The address a listener for `addr` binds to: its ipv6 address if it has one, otherwise ipv4
+pub fn listen_addr(addr: &str, use_ipv6: bool) -> anyhow::Result<SocketAddr> {
+    let addresses = resolver::get_addresses(addr)?;
+
+    #[allow(clippy::if_same_then_else)]
+    if use_ipv6 {
+        addresses.ipv6
+    } else if addresses.ipv6.is_some() {
+        addresses.ipv6
+    } else {
+        addresses.ipv4
+    }
+    .ok_or(anyhow!("Failed to resolve {addr}"))
+}
+
+// replaces the port of an address like `myaddr.com:8080` or `[::1]:8080`
+fn with_port(addr: &str, port: u16) -> String {
+    let host = config::host_part(addr);
+
+    if host.contains(':') {
+        format!("[{host}]:{port}")
+    } else {
+        format!("{host}:{port}")
+    }
+}
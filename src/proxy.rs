@@ -1,59 +1,56 @@
+use std::env;
 use std::error::Error;
-use std::{env, io::BufReader};
 
-use actix_web::http::Method;
-use actix_web::{web, App, HttpRequest, HttpResponse, HttpResponseBuilder, HttpServer};
+use axum::{
+    body::{Body, BoxBody, StreamBody},
+    http::{header::CONTENT_LENGTH, HeaderMap, HeaderValue, Method, Request, StatusCode},
+    response::{IntoResponse, Response},
+    Router,
+};
 use once_cell::sync::Lazy;
 use qstring::QString;
 use regex::Regex;
-use reqwest::{Body, Client, Request, Url};
-use rustls::{server::ServerConfig, Certificate, PrivateKey};
-use rustls_pemfile::{certs, rsa_private_keys};
-
-use crate::{config::Config, resolver};
-
-pub async fn start_proxy(config: &Config) -> std::io::Result<()> {
-    let server = HttpServer::new(|| {
-        // match all requests
-        App::new().default_service(web::to(index))
-    });
-
-    let proxy_addr =
-        resolver::get_addresses(&config.addresses.proxy).expect("Failed to resolve proxy address");
-    #[allow(clippy::if_same_then_else)]
-    let proxy_addr = if config.addresses.use_ipv6.as_ref().is_some_and(|i| *i) {
-        proxy_addr.ipv6.as_ref()
-    } else if proxy_addr.ipv6.as_ref().is_some() {
-        proxy_addr.ipv6.as_ref()
-    } else {
-        proxy_addr.ipv4.as_ref()
-    }
-    .expect("Failed to resolve frontend address");
+use reqwest::{Client, Url};
+
+use crate::{config::Config, ports, tls};
+
+/// Paths the proxy serves. Used to route them from the frontend in single port mode
+pub static PROXY_ROUTES: &[&str] = &[
+    "/videoplayback",
+    "/api/manifest/*path",
+    "/api/timedtext",
+    "/vi/*path",
+    "/vi_webp/*path",
+    "/sb/*path",
+    "/ggpht/*path",
+    "/a/*path",
+    "/an/*path",
+    "/an_webp/*path",
+    "/ytc/*path",
+];
 
-    if config.addresses.use_ssl.as_ref().is_some_and(|i| *i) {
-        let cert_file = std::fs::read(config.addresses.ssl_cert.as_ref().unwrap()).unwrap();
-        let key_file = std::fs::read(config.addresses.ssl_key.as_ref().unwrap()).unwrap();
+pub async fn start_proxy(config: &Config) -> anyhow::Result<()> {
+    // match all requests
+    let app = Router::new().fallback(index);
 
-        let cert_file = &mut BufReader::new(&*cert_file);
-        let key_file = &mut BufReader::new(&*key_file);
+    let proxy_addr = ports::listen_addr(
+        &config.addresses.proxy,
+        config.addresses.use_ipv6.as_ref().is_some_and(|i| *i),
+    )?;
 
-        let cert_chain = certs(cert_file)
-            .unwrap()
-            .into_iter()
-            .map(Certificate)
-            .collect();
-        let mut keys = rsa_private_keys(key_file).unwrap();
-
-        let rustls_config = ServerConfig::builder()
-            .with_safe_defaults()
-            .with_no_client_auth()
-            .with_single_cert(cert_chain, PrivateKey(keys.remove(0)))
-            .unwrap();
+    if config.addresses.use_ssl.as_ref().is_some_and(|i| *i) {
+        let rustls_config = tls::rustls_config(config, tls::Listener::Proxy).await?;
 
-        server.bind_rustls(proxy_addr, rustls_config)?.run().await
+        axum_server::bind_rustls(proxy_addr, rustls_config)
+            .serve(app.into_make_service())
+            .await?;
     } else {
-        server.bind(proxy_addr)?.run().await
+        axum::Server::try_bind(&proxy_addr)?
+            .serve(app.into_make_service())
+            .await?;
     }
+
+    Ok(())
 }
 
 static RE_DOMAIN: Lazy<Regex> =
@@ -106,12 +103,20 @@ const ALLOWED_DOMAINS: [&str; 8] = [
     "ajay.app",
 ];
 
-fn add_headers(response: &mut HttpResponseBuilder) {
-    response
-        .append_header(("Access-Control-Allow-Origin", "*"))
-        .append_header(("Access-Control-Allow-Headers", "*"))
-        .append_header(("Access-Control-Allow-Methods", "*"))
-        .append_header(("Access-Control-Max-Age", "1728000"));
+fn add_headers(headers: &mut HeaderMap) {
+    headers.append("Access-Control-Allow-Origin", HeaderValue::from_static("*"));
+    headers.append(
+        "Access-Control-Allow-Headers",
+        HeaderValue::from_static("*"),
+    );
+    headers.append(
+        "Access-Control-Allow-Methods",
+        HeaderValue::from_static("*"),
+    );
+    headers.append(
+        "Access-Control-Max-Age",
+        HeaderValue::from_static("1728000"),
+    );
 }
 
 fn is_header_allowed(header: &str) -> bool {
@@ -132,19 +137,27 @@ fn is_header_allowed(header: &str) -> bool {
     )
 }
 
-async fn index(req: HttpRequest) -> Result<HttpResponse, Box<dyn Error>> {
+/// Proxies a request to one of the `ALLOWED_DOMAINS`. This can be routed from any router
+pub async fn index(req: Request<Body>) -> Response<BoxBody> {
+    match proxy(req).await {
+        Ok(response) => response,
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+async fn proxy(req: Request<Body>) -> Result<Response<BoxBody>, Box<dyn Error + Send + Sync>> {
     if req.method() == Method::OPTIONS {
-        let mut response = HttpResponse::Ok();
-        add_headers(&mut response);
-        return Ok(response.finish());
+        let mut headers = HeaderMap::new();
+        add_headers(&mut headers);
+        return Ok((StatusCode::OK, headers).into_response());
     } else if req.method() != Method::GET && req.method() != Method::HEAD {
-        let mut response = HttpResponse::MethodNotAllowed();
-        add_headers(&mut response);
-        return Ok(response.finish());
+        let mut headers = HeaderMap::new();
+        add_headers(&mut headers);
+        return Ok((StatusCode::METHOD_NOT_ALLOWED, headers).into_response());
     }
 
     // parse query string
-    let query = QString::from(req.query_string());
+    let query = QString::from(req.uri().query().unwrap_or_default());
 
     let res = query.get("host");
     let res = res.map(|s| s.to_string());
@@ -155,9 +168,6 @@ async fn index(req: HttpRequest) -> Result<HttpResponse, Box<dyn Error>> {
 
     let rewrite = query.get("rewrite") != Some("false");
 
-    #[cfg(feature = "avif")]
-    let avif = query.get("avif") == Some("true");
-
     let host = res.unwrap();
     let domain = RE_DOMAIN.captures(host.as_str());
 
@@ -171,7 +181,8 @@ async fn index(req: HttpRequest) -> Result<HttpResponse, Box<dyn Error>> {
         return Err("Domain not allowed".into());
     }
 
-    let video_playback = req.path().eq("/videoplayback");
+    let path = req.uri().path();
+    let video_playback = path.eq("/videoplayback");
     let is_android = video_playback && query.get("c").unwrap_or("").eq("ANDROID");
 
     let qs = {
@@ -183,7 +194,7 @@ async fn index(req: HttpRequest) -> Result<HttpResponse, Box<dyn Error>> {
         QString::new(collected)
     };
 
-    let mut url = Url::parse(&format!("https://{}{}", host, req.path()))?;
+    let mut url = Url::parse(&format!("https://{}{}", host, path))?;
     url.set_query(Some(qs.to_string().as_str()));
 
     let method = {
@@ -194,10 +205,10 @@ async fn index(req: HttpRequest) -> Result<HttpResponse, Box<dyn Error>> {
         }
     };
 
-    let mut request = Request::new(method, url);
+    let mut request = reqwest::Request::new(method, url);
 
     if !is_android && video_playback {
-        request.body_mut().replace(Body::from("x\0"));
+        request.body_mut().replace(reqwest::Body::from("x\0"));
     }
 
     let request_headers = request.headers_mut();
@@ -220,95 +231,19 @@ async fn index(req: HttpRequest) -> Result<HttpResponse, Box<dyn Error>> {
 
     let resp = resp?;
 
-    let mut response = HttpResponse::build(resp.status());
+    let status = resp.status();
+    let mut headers = HeaderMap::new();
 
-    add_headers(&mut response);
+    add_headers(&mut headers);
 
     for (key, value) in resp.headers() {
         if is_header_allowed(key.as_str()) {
-            response.append_header((key.as_str(), value.as_bytes()));
+            headers.append(key, value.clone());
         }
     }
 
     if rewrite {
         if let Some(content_type) = resp.headers().get("content-type") {
-            #[cfg(feature = "avif")]
-            if content_type == "image/webp" || content_type == "image/jpeg" && avif {
-                let resp_bytes = resp.bytes().await.unwrap();
-                let (tx, rx) = oneshot::channel::<(Vec<u8>, &'static str)>();
-                spawn_blocking(|| {
-                    use ravif::{Encoder, Img};
-                    use rgb::FromSlice;
-
-                    let image = image::load_from_memory(&resp_bytes).unwrap();
-
-                    let width = image.width() as usize;
-                    let height = image.height() as usize;
-
-                    let buf = image.into_rgb8();
-                    let buf = buf.as_raw().as_rgb();
-
-                    let buffer = Img::new(buf, width, height);
-
-                    let res = Encoder::new()
-                        .with_quality(80f32)
-                        .with_speed(7)
-                        .encode_rgb(buffer);
-
-                    return if let Ok(res) = res {
-                        tx.send((res.avif_file.to_vec(), "image/avif")).unwrap();
-                    } else {
-                        tx.send((resp_bytes.into(), "image/jpeg")).unwrap();
-                    };
-                });
-                let (body, content_type) = rx.await.unwrap();
-                response.content_type(content_type);
-                return Ok(response.body(body));
-            }
-
-            #[cfg(feature = "webp")]
-            if content_type == "image/jpeg" {
-                let resp_bytes = resp.bytes().await.unwrap();
-                let (tx, rx) = oneshot::channel::<(Vec<u8>, &'static str)>();
-                spawn_blocking(|| {
-                    use libwebp_sys::{WebPEncodeRGB, WebPFree};
-
-                    let image = image::load_from_memory(&resp_bytes).unwrap();
-                    let width = image.width();
-                    let height = image.height();
-
-                    let quality = 85;
-
-                    let data = image.as_rgb8().unwrap().as_raw();
-
-                    let bytes: Vec<u8> = unsafe {
-                        let mut out_buf = std::ptr::null_mut();
-                        let stride = width as i32 * 3;
-                        let len: usize = WebPEncodeRGB(
-                            data.as_ptr(),
-                            width as i32,
-                            height as i32,
-                            stride,
-                            quality as f32,
-                            &mut out_buf,
-                        );
-                        let vec = std::slice::from_raw_parts(out_buf, len).into();
-                        WebPFree(out_buf as *mut _);
-                        vec
-                    };
-
-                    if bytes.len() < resp_bytes.len() {
-                        tx.send((bytes, "image/webp")).unwrap();
-                        return;
-                    }
-
-                    tx.send((resp_bytes.into(), "image/jpeg")).unwrap();
-                });
-                let (body, content_type) = rx.await.unwrap();
-                response.content_type(content_type);
-                return Ok(response.body(body));
-            }
-
             if content_type == "application/x-mpegurl"
                 || content_type == "application/vnd.apple.mpegurl"
             {
@@ -330,7 +265,7 @@ async fn index(req: HttpRequest) -> Result<HttpResponse, Box<dyn Error>> {
                     .collect::<Vec<String>>()
                     .join("\n");
 
-                return Ok(response.body(modified));
+                return Ok((status, headers, modified).into_response());
             }
             if content_type == "video/vnd.mpeg.dash.mpd" || content_type == "application/dash+xml" {
                 let mut resp_str = resp.text().await.unwrap();
@@ -341,17 +276,17 @@ async fn index(req: HttpRequest) -> Result<HttpResponse, Box<dyn Error>> {
                     let new_url = localize_url(url, host.as_str());
                     resp_str = resp_str.replace(url, new_url.as_str());
                 }
-                return Ok(response.body(resp_str));
+                return Ok((status, headers, resp_str).into_response());
             }
         }
     }
 
     if let Some(content_length) = resp.headers().get("content-length") {
-        response.append_header(("content-length", content_length));
+        headers.append(CONTENT_LENGTH, content_length.clone());
     }
 
     // Stream response
-    Ok(response.streaming(resp.bytes_stream()))
+    Ok((status, headers, StreamBody::new(resp.bytes_stream())).into_response())
 }
 
 fn localize_url(url: &str, host: &str) -> String {
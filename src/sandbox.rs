@@ -0,0 +1,296 @@
+use std::{path::Path, process::Command};
+
+use crate::config::Sandbox;
+
+/// Applies the enabled `sandbox` options to the backend's java command
+#[cfg(target_os = "linux")]
+pub fn apply(
+    command: &mut Command,
+    sandbox: &Sandbox,
+    java: &Path,
+    jar: &Path,
+    data_local: &Path,
+) -> anyhow::Result<()> {
+    linux::apply(command, sandbox, java, jar, data_local)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn apply(
+    _command: &mut Command,
+    sandbox: &Sandbox,
+    _java: &Path,
+    _jar: &Path,
+    _data_local: &Path,
+) -> anyhow::Result<()> {
+    if sandbox.enabled() {
+        eprintln!("warning: the backend sandbox only works on linux, ignoring it");
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::{
+        env,
+        ffi::CString,
+        fs::{self, OpenOptions},
+        io, mem,
+        os::{
+            fd::{AsRawFd, FromRawFd, OwnedFd},
+            unix::{fs::OpenOptionsExt, process::CommandExt},
+        },
+        path::{Path, PathBuf},
+        process::Command,
+        ptr,
+    };
+
+    use anyhow::anyhow;
+
+    use crate::config::{self, Sandbox};
+
+    // from linux/landlock.h
+    const LANDLOCK_CREATE_RULESET_VERSION: libc::c_uint = 1;
+    const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+    const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+    const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+    const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+    const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+    // every filesystem access right of the first landlock abi
+    const ACCESS_FS_ALL: u64 = (1 << 13) - 1;
+
+    const ACCESS_READ: u64 = ACCESS_FS_EXECUTE | ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR;
+    // rights that apply to files, rather than the contents of directories
+    const ACCESS_FILE: u64 = ACCESS_FS_EXECUTE | ACCESS_FS_WRITE_FILE | ACCESS_FS_READ_FILE;
+
+    // what the jvm needs from the system: libraries, certificates, dns config, and /proc and /sys
+    // to find out about the machine. Landlock follows symlinks, and with systemd-resolved
+    // /etc/resolv.conf points into /run
+    const SYSTEM_READ: [&str; 8] = [
+        "/usr", "/lib", "/lib64", "/bin", "/etc", "/run", "/proc", "/sys",
+    ];
+    // /tmp has the jvm's perf data, and /dev has /dev/null and /dev/urandom
+    const SYSTEM_WRITE: [&str; 2] = ["/tmp", "/dev"];
+
+    #[repr(C)]
+    struct RulesetAttr {
+        handled_access_fs: u64,
+    }
+
+    #[repr(C, packed)]
+    struct PathBeneathAttr {
+        allowed_access: u64,
+        parent_fd: i32,
+    }
+
+    pub fn apply(
+        command: &mut Command,
+        sandbox: &Sandbox,
+        java: &Path,
+        jar: &Path,
+        data_local: &Path,
+    ) -> anyhow::Result<()> {
+        if let Some(user) = &sandbox.user {
+            let (uid, gid) = lookup_user(user)?;
+            command.uid(uid).gid(gid);
+        }
+
+        let process_group = sandbox.process_group.as_ref().is_some_and(|i| *i);
+        if process_group {
+            command.process_group(0);
+        }
+
+        let landlock = sandbox.landlock.as_ref().is_some_and(|i| *i);
+        let ruleset = if landlock {
+            let mut read = SYSTEM_READ.map(PathBuf::from).to_vec();
+            read.extend(jre_dir(java));
+            read.push(jar.to_path_buf());
+
+            let mut write = SYSTEM_WRITE.map(PathBuf::from).to_vec();
+            write.push(data_local.to_path_buf());
+            for path in sandbox.landlock_paths.iter().flatten() {
+                write.push(config::resolve_path(path)?);
+            }
+
+            Some(landlock_ruleset(&read, &write)?)
+        } else {
+            None
+        };
+
+        // landlock can't be used without it
+        let no_new_privs = landlock || sandbox.no_new_privs.as_ref().is_some_and(|i| *i);
+
+        let mut limits = Vec::new();
+        if let Some(mb) = sandbox.memory_limit_mb {
+            limits.push((libc::RLIMIT_AS, (mb * 1024 * 1024) as libc::rlim_t));
+        }
+        if let Some(files) = sandbox.open_files_limit {
+            limits.push((libc::RLIMIT_NOFILE, files as libc::rlim_t));
+        }
+
+        let pre_exec = move || {
+            // this runs in the forked child, so only async signal safe calls from here on
+
+            for (resource, limit) in &limits {
+                let rlimit = libc::rlimit {
+                    rlim_cur: *limit,
+                    rlim_max: *limit,
+                };
+
+                if unsafe { libc::setrlimit(*resource, &rlimit) } != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+
+            // ctrl+c no longer reaches it in its own group, so make sure it doesn't outlive us
+            if process_group && unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            if no_new_privs && unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            if let Some(ruleset) = &ruleset {
+                let result = unsafe {
+                    libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0)
+                };
+
+                if result != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+
+            Ok(())
+        };
+
+        unsafe { command.pre_exec(pre_exec) };
+
+        Ok(())
+    }
+
+    /// Makes a landlock ruleset only allowing access beneath `read` and `write`
+    fn landlock_ruleset(read: &[PathBuf], write: &[PathBuf]) -> anyhow::Result<OwnedFd> {
+        let abi = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                ptr::null::<RulesetAttr>(),
+                0,
+                LANDLOCK_CREATE_RULESET_VERSION,
+            )
+        };
+
+        if abi < 1 {
+            return Err(anyhow!(
+                "landlock isn't available, it needs linux 5.13+ with landlock enabled"
+            ));
+        }
+
+        let attr = RulesetAttr {
+            handled_access_fs: ACCESS_FS_ALL,
+        };
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr,
+                mem::size_of::<RulesetAttr>(),
+                0,
+            )
+        };
+
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        let ruleset = unsafe { OwnedFd::from_raw_fd(fd as i32) };
+
+        let rules = read
+            .iter()
+            .map(|path| (path, ACCESS_READ))
+            .chain(write.iter().map(|path| (path, ACCESS_FS_ALL)));
+
+        for (path, access) in rules {
+            // not every system has all of the system dirs
+            let Ok(file) = OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_PATH | libc::O_CLOEXEC)
+                .open(path)
+            else {
+                continue;
+            };
+
+            let access = if fs::metadata(path)?.is_dir() {
+                access
+            } else {
+                access & ACCESS_FILE
+            };
+
+            let rule = PathBeneathAttr {
+                allowed_access: access,
+                parent_fd: file.as_raw_fd(),
+            };
+
+            let result = unsafe {
+                libc::syscall(
+                    libc::SYS_landlock_add_rule,
+                    ruleset.as_raw_fd(),
+                    LANDLOCK_RULE_PATH_BENEATH,
+                    &rule,
+                    0,
+                )
+            };
+
+            if result != 0 {
+                return Err(anyhow!(
+                    "Failed to allow {} in landlock: {}",
+                    path.display(),
+                    io::Error::last_os_error()
+                ));
+            }
+        }
+
+        Ok(ruleset)
+    }
+
+    /// The jre `java` belongs to, eg: /usr/lib/jvm/java-17-openjdk-amd64 for /usr/bin/java
+    fn jre_dir(java: &Path) -> Option<PathBuf> {
+        let java = if java.components().count() > 1 {
+            java.to_path_buf()
+        } else {
+            env::split_paths(&env::var_os("PATH")?)
+                .map(|dir| dir.join(java))
+                .find(|path| path.is_file())?
+        };
+
+        // bin/java, usually through a symlink or two
+        let java = fs::canonicalize(java).ok()?;
+        Some(java.parent()?.parent()?.to_path_buf())
+    }
+
+    fn lookup_user(user: &str) -> anyhow::Result<(u32, u32)> {
+        let name = CString::new(user)?;
+        let mut passwd = unsafe { mem::zeroed::<libc::passwd>() };
+        let mut buf = vec![0; 4096];
+        let mut result = ptr::null_mut();
+
+        let error = unsafe {
+            libc::getpwnam_r(
+                name.as_ptr(),
+                &mut passwd,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            )
+        };
+
+        if error != 0 {
+            return Err(io::Error::from_raw_os_error(error).into());
+        }
+
+        if result.is_null() {
+            return Err(anyhow!("user {user} doesn't exist"));
+        }
+
+        Ok((passwd.pw_uid, passwd.pw_gid))
+    }
+}
@@ -0,0 +1,380 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use axum::{
+    extract::State,
+    http::{Method, Request, StatusCode},
+    middleware::{self, Next},
+    response::{Html, IntoResponse, Response},
+    routing::{get, post},
+    Form, Json, Router,
+};
+use directories::ProjectDirs;
+use qstring::QString;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::sync::oneshot;
+use tokio_postgres::NoTls;
+
+use crate::{config::Config, resolver};
+
+#[derive(Debug)]
+struct Setup {
+    shutdown: Mutex<Option<oneshot::Sender<()>>>,
+    // printed to the console and needed for every request, so only whoever started us can set up
+    token: String,
+    // what the Host header may be, so other sites can't reach us through dns rebinding
+    hosts: Vec<String>,
+}
+
+/// Whether there's no config yet, in which case `run_setup` should be run first
+pub fn is_first_run() -> anyhow::Result<bool> {
+    Ok(!Config::config_path()?.exists())
+}
+
+/// Serves the setup page on the default frontend address until a config has been saved
+pub async fn run_setup() -> anyhow::Result<()> {
+    let defaults = Config::default();
+
+    let setup_addr = resolver::get_addresses(&defaults.addresses.frontend)?;
+    let setup_addr = setup_addr
+        .ipv4
+        .or(setup_addr.ipv6)
+        .ok_or(anyhow!("Failed to resolve setup address"))?;
+
+    let mut token = [0; 16];
+    SystemRandom::new()
+        .fill(&mut token)
+        .map_err(|_| anyhow!("Failed to generate setup token"))?;
+    let token = token.iter().map(|b| format!("{b:02x}")).collect::<String>();
+
+    let (tx, rx) = oneshot::channel();
+    let setup = Arc::new(Setup {
+        shutdown: Mutex::new(Some(tx)),
+        token,
+        hosts: vec![
+            setup_addr.to_string(),
+            format!("localhost:{}", setup_addr.port()),
+        ],
+    });
+
+    let app = Router::new()
+        .route("/", get(setup_page))
+        .route("/test-db", post(test_db))
+        .route("/save", post(save))
+        .layer(middleware::from_fn_with_state(setup.clone(), guard))
+        .with_state(setup.clone());
+
+    let server = axum::Server::try_bind(&setup_addr)?;
+
+    println!(
+        "No config.toml found. Open http://{setup_addr}/?token={} to finish setting up",
+        setup.token
+    );
+
+    server
+        .serve(app.into_make_service())
+        .with_graceful_shutdown(async {
+            rx.await.ok();
+        })
+        .await?;
+
+    Ok(())
+}
+
+/// Only lets through requests for our own address, from our own page, with the token.
+/// The token is in the url for the page, and a header for the api, which other origins can't
+/// send without a cors preflight we never answer
+async fn guard<B>(State(setup): State<Arc<Setup>>, request: Request<B>, next: Next<B>) -> Response {
+    let headers = request.headers();
+    let header = |name: &str| headers.get(name).and_then(|h| h.to_str().ok());
+
+    let host_ok = header("host").is_some_and(|host| setup.hosts.iter().any(|h| h == host));
+    // same origin fetches may leave it out
+    let origin_ok = match header("origin") {
+        Some(origin) => setup
+            .hosts
+            .iter()
+            .any(|host| origin == format!("http://{host}")),
+        None => true,
+    };
+
+    let token = if request.method() == Method::GET {
+        request
+            .uri()
+            .query()
+            .map(QString::from)
+            .and_then(|query| query.get("token").map(str::to_string))
+    } else {
+        header("x-setup-token").map(str::to_string)
+    };
+    let token_ok = token.is_some_and(|token| token == setup.token);
+
+    if !(host_ok && origin_ok && token_ok) {
+        return (
+            StatusCode::FORBIDDEN,
+            "403 Forbidden: open the setup link printed to the console",
+        )
+            .into_response();
+    }
+
+    next.run(request).await
+}
+
+#[derive(Debug, Deserialize)]
+struct DbForm {
+    database: String,
+    db_connection_url: String,
+    db_username: String,
+    db_password: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct SetupForm {
+    #[serde(flatten)]
+    db: DbForm,
+    frontend: String,
+    backend: String,
+    proxy: String,
+    ssl: String,
+    backend_ssl_proxy: String,
+    ssl_cert: String,
+    ssl_key: String,
+    acme_contact: String,
+}
+
+// empty form fields are unset options
+fn non_empty(value: &str) -> Option<String> {
+    let value = value.trim();
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+async fn setup_page(State(setup): State<Arc<Setup>>) -> Html<String> {
+    Html(SETUP_PAGE.replace("{{token}}", &setup.token))
+}
+
+async fn test_db(Form(form): Form<DbForm>) -> Json<Value> {
+    match check_db(&form).await {
+        Ok(()) => Json(json!({ "ok": true })),
+        Err(e) => Json(json!({ "ok": false, "error": e.to_string() })),
+    }
+}
+
+async fn save(State(setup): State<Arc<Setup>>, Form(form): Form<SetupForm>) -> impl IntoResponse {
+    let result = async {
+        check_db(&form.db).await?;
+
+        let config = make_config(&form)?;
+        config.save()?;
+
+        Ok::<_, anyhow::Error>(config.addresses.frontend_uri())
+    }
+    .await;
+
+    match result {
+        Ok(frontend) => {
+            // the real frontend takes over this address once the setup server is down
+            if let Some(tx) = setup.shutdown.lock().unwrap().take() {
+                tx.send(()).ok();
+            }
+
+            Json(json!({ "ok": true, "frontend": frontend }))
+        }
+
+        Err(e) => Json(json!({ "ok": false, "error": e.to_string() })),
+    }
+}
+
+fn make_config(form: &SetupForm) -> anyhow::Result<Config> {
+    let mut config = Config::default();
+
+    match form.db.database.as_str() {
+        "hsqldb" => {
+            let project_dir = ProjectDirs::from("", "", "youtube-server")
+                .ok_or(anyhow!("Failed to get project directory"))?;
+            let db_path = project_dir.data_local_dir().join("db").join("piped");
+
+            config.backend.db_connection_url = format!(
+                "jdbc:hsqldb:file:{};sql.syntax_pgs=true",
+                db_path.to_str().ok_or(anyhow!("invalid data directory"))?
+            );
+            config.backend.db_connection_driver = Some("org.hsqldb.jdbcDriver".to_string());
+            config.backend.db_dialect = Some("org.hibernate.dialect.HSQLDialect".to_string());
+        }
+
+        _ => {
+            config.backend.db_connection_url = form.db.db_connection_url.trim().to_string();
+            config.backend.db_username = form.db.db_username.clone();
+            config.backend.db_password = form.db.db_password.clone();
+        }
+    }
+
+    let addresses = &mut config.addresses;
+    addresses.frontend = non_empty(&form.frontend).unwrap_or(addresses.frontend.clone());
+    addresses.backend = non_empty(&form.backend).unwrap_or(addresses.backend.clone());
+    addresses.proxy = non_empty(&form.proxy).unwrap_or(addresses.proxy.clone());
+
+    match form.ssl.as_str() {
+        "files" => {
+            addresses.use_ssl = Some(true);
+            addresses.ssl_cert =
+                Some(non_empty(&form.ssl_cert).ok_or(anyhow!("certificate missing"))?);
+            addresses.ssl_key =
+                Some(non_empty(&form.ssl_key).ok_or(anyhow!("private key missing"))?);
+        }
+
+        "acme" => {
+            addresses.use_ssl = Some(true);
+            config.acme.enabled = true;
+            config.acme.contact = non_empty(&form.acme_contact)
+                .map(|c| vec![format!("mailto:{c}")])
+                .unwrap_or_default();
+        }
+
+        _ => (),
+    }
+
+    if config.addresses.use_ssl.is_some() {
+        // one certificate for everything, so serve the api from the frontend unless asked not to
+        match non_empty(&form.backend_ssl_proxy) {
+            Some(proxy) => config.addresses.backend_ssl_proxy = Some(proxy),
+            None => config.addresses.backend_path = Some("/api".to_string()),
+        }
+    }
+
+    Ok(config)
+}
+
+/// Makes sure the database can actually be connected to, so the backend doesn't fail later
+async fn check_db(form: &DbForm) -> anyhow::Result<()> {
+    if form.database == "hsqldb" {
+        return Ok(());
+    }
+
+    // eg: jdbc:postgresql://localhost:5432/piped
+    let url = form.db_connection_url.trim();
+    let rest = url
+        .strip_prefix("jdbc:postgresql://")
+        .ok_or(anyhow!("connection url must start with jdbc:postgresql://"))?;
+    let (authority, database) = rest
+        .split_once('/')
+        .ok_or(anyhow!("connection url is missing the database name"))?;
+    let database = database.split('?').next().unwrap_or(database);
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse::<u16>()?),
+        None => (authority, 5432),
+    };
+
+    let (client, connection) = tokio_postgres::Config::new()
+        .host(host)
+        .port(port)
+        .dbname(database)
+        .user(&form.db_username)
+        .password(&form.db_password)
+        .connect_timeout(Duration::from_secs(5))
+        .connect(NoTls)
+        .await?;
+
+    tokio::spawn(connection);
+
+    client.simple_query("SELECT 1").await?;
+
+    Ok(())
+}
+
+static SETUP_PAGE: &str = r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>youtube-server setup</title>
+<style>
+body { font-family: sans-serif; max-width: 40em; margin: 2em auto; padding: 0 1em; }
+fieldset { margin-bottom: 1em; }
+label { display: block; margin: .5em 0; }
+input[type=text], input[type=password] { width: 100%; box-sizing: border-box; }
+.hidden { display: none; }
+#status { font-weight: bold; }
+</style>
+</head>
+<body>
+<h1>youtube-server setup</h1>
+<p>No <code>config.toml</code> was found next to the executable. Fill this in to create one, everything can be changed later in the file.</p>
+<form id="setup">
+<fieldset>
+<legend>Database</legend>
+<label><input type="radio" name="database" value="hsqldb" checked> Built in file database (HSQLDB, nothing to install)</label>
+<label><input type="radio" name="database" value="postgresql"> PostgreSQL</label>
+<div id="postgresql" class="hidden">
+<label>Connection url <input type="text" name="db_connection_url" value="jdbc:postgresql://localhost:5432/piped"></label>
+<label>Username <input type="text" name="db_username" value="piped"></label>
+<label>Password <input type="password" name="db_password"></label>
+<button type="button" id="test">Test connection</button>
+</div>
+</fieldset>
+<fieldset>
+<legend>Addresses</legend>
+<label>Frontend <input type="text" name="frontend" placeholder="localhost:8080"></label>
+<label>Backend <input type="text" name="backend" placeholder="localhost:8081"></label>
+<label>Proxy <input type="text" name="proxy" placeholder="localhost:8082"></label>
+</fieldset>
+<fieldset>
+<legend>SSL</legend>
+<label><input type="radio" name="ssl" value="none" checked> None</label>
+<label><input type="radio" name="ssl" value="files"> Certificate files (PEM)</label>
+<label><input type="radio" name="ssl" value="acme"> Automatic (Let's Encrypt)</label>
+<div id="files" class="hidden">
+<label>Certificate <input type="text" name="ssl_cert" placeholder="cert.pem"></label>
+<label>Private key <input type="text" name="ssl_key" placeholder="key.pem"></label>
+</div>
+<div id="acme" class="hidden">
+<label>Contact email <input type="text" name="acme_contact"></label>
+</div>
+<label class="ssl-only hidden">Backend ssl proxy (leave empty to serve the api from the frontend at /api) <input type="text" name="backend_ssl_proxy"></label>
+</fieldset>
+<button type="submit">Save and start</button>
+<p id="status"></p>
+</form>
+<script>
+const form = document.getElementById("setup");
+const status = document.getElementById("status");
+const show = (id, visible) => document.getElementById(id).classList.toggle("hidden", !visible);
+form.addEventListener("change", () => {
+    show("postgresql", form.database.value === "postgresql");
+    show("files", form.ssl.value === "files");
+    show("acme", form.ssl.value === "acme");
+    document.querySelector(".ssl-only").classList.toggle("hidden", form.ssl.value === "none");
+});
+const token = "{{token}}";
+const post = async (url) => {
+    const res = await fetch(url, {
+        method: "POST",
+        headers: { "x-setup-token": token },
+        body: new URLSearchParams(new FormData(form)),
+    });
+    return res.json();
+};
+document.getElementById("test").addEventListener("click", async () => {
+    status.textContent = "Connecting...";
+    const res = await post("/test-db");
+    status.textContent = res.ok ? "Connected!" : "Failed: " + res.error;
+});
+form.addEventListener("submit", async (e) => {
+    e.preventDefault();
+    status.textContent = "Saving...";
+    const res = await post("/save");
+    if (!res.ok) {
+        status.textContent = "Failed: " + res.error;
+        return;
+    }
+    status.textContent = "Saved! Starting up, this can take a minute...";
+    setTimeout(() => location.href = res.frontend, 15000);
+});
+</script>
+</body>
+</html>
+"#;
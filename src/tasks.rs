@@ -0,0 +1,109 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use tokio::{
+    task::{Id, JoinSet},
+    time,
+};
+
+use crate::config::{OnFailure, Supervisor};
+
+type TaskFuture = Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send>>;
+type Start = Arc<dyn Fn() -> TaskFuture + Send + Sync>;
+
+// a task running this long is healthy again, so its restarts are counted from zero
+const STABLE_UPTIME: Duration = Duration::from_secs(10 * 60);
+
+/// The long running parts of the server, eg: the listeners and the backend.
+/// None of them are meant to ever finish, so one that does is handled according to
+/// the `supervisor` config. Whatever is still running is aborted when this is dropped
+#[derive(Default)]
+pub struct Tasks {
+    set: JoinSet<anyhow::Result<()>>,
+    names: HashMap<Id, (&'static str, Instant)>,
+    // how to start the restartable tasks again
+    starts: HashMap<&'static str, Start>,
+    restarts: HashMap<&'static str, u32>,
+}
+
+impl Tasks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs a task that can be restarted by calling `start` again
+    pub fn spawn<F, Fut>(&mut self, name: &'static str, start: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        let start: Start = Arc::new(move || Box::pin(start()));
+
+        self.spawn_once(name, start());
+        self.starts.insert(name, start);
+    }
+
+    /// Runs a task that can't be restarted, so its failure always shuts down
+    pub fn spawn_once<F>(&mut self, name: &'static str, task: F)
+    where
+        F: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        let handle = self.set.spawn(task);
+        self.names.insert(handle.id(), (name, Instant::now()));
+    }
+
+    /// Waits until a task stops and isn't restarted, and returns why it stopped
+    pub async fn supervise(&mut self, supervisor: &Supervisor) -> anyhow::Error {
+        loop {
+            let Some(joined) = self.set.join_next_with_id().await else {
+                return anyhow!("nothing left running");
+            };
+
+            let (id, error) = match joined {
+                Ok((id, Ok(()))) => (id, anyhow!("stopped unexpectedly")),
+                Ok((id, Err(e))) => (id, e),
+                Err(e) => (e.id(), anyhow!("panicked: {e}")),
+            };
+
+            let (name, started) = self.names.remove(&id).unwrap_or(("task", Instant::now()));
+            let error = error.context(format!("{name} failed"));
+
+            let Some(start) = self
+                .starts
+                .get(name)
+                .filter(|_| supervisor.on_failure == OnFailure::Restart)
+                .cloned()
+            else {
+                return error;
+            };
+
+            let restarts = self.restarts.entry(name).or_default();
+            if started.elapsed() >= STABLE_UPTIME {
+                *restarts = 0;
+            }
+            if *restarts >= supervisor.max_restarts() {
+                return error.context(format!("{name} was restarted too many times"));
+            }
+            *restarts += 1;
+
+            let delay = supervisor.restart_delay();
+            eprintln!(
+                "error: {error:?}\nrestarting {name} in {delay:?} ({restarts}/{})",
+                supervisor.max_restarts()
+            );
+
+            let handle = self.set.spawn(async move {
+                time::sleep(delay).await;
+                start().await
+            });
+            self.names
+                .insert(handle.id(), (name, Instant::now() + delay));
+        }
+    }
+}
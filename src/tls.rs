@@ -0,0 +1,94 @@
+use std::{fs, path::Path, sync::Arc};
+
+use anyhow::{anyhow, Context};
+use axum_server::tls_rustls::RustlsConfig;
+use rustls::{Certificate, PrivateKey, ServerConfig};
+use rustls_pemfile::Item;
+
+use crate::{
+    acme,
+    config::{resolve_path, Config},
+};
+
+/// The ssl listeners, each of which may have its own certificate
+#[derive(Debug, Clone, Copy)]
+pub enum Listener {
+    Frontend,
+    BackendSslProxy,
+    Proxy,
+}
+
+/// Makes the rustls config for an ssl listener, either from acme or from the configured pem files
+pub async fn rustls_config(config: &Config, listener: Listener) -> anyhow::Result<RustlsConfig> {
+    if config.acme.enabled {
+        return Ok(RustlsConfig::from_config(acme::server_config()?));
+    }
+
+    let addresses = &config.addresses;
+    let (cert, key) = match listener {
+        Listener::Frontend => (&addresses.frontend_ssl_cert, &addresses.frontend_ssl_key),
+        Listener::BackendSslProxy => (&addresses.backend_ssl_cert, &addresses.backend_ssl_key),
+        Listener::Proxy => (&addresses.proxy_ssl_cert, &addresses.proxy_ssl_key),
+    };
+
+    // per listener certificates fall back to the shared one
+    let cert = cert
+        .as_ref()
+        .or(addresses.ssl_cert.as_ref())
+        .ok_or(anyhow!("ssl_cert missing for {listener:?}"))?;
+    let key = key
+        .as_ref()
+        .or(addresses.ssl_key.as_ref())
+        .ok_or(anyhow!("ssl_key missing for {listener:?}"))?;
+
+    let server_config = server_config(&resolve_path(cert)?, &resolve_path(key)?)?;
+
+    Ok(RustlsConfig::from_config(Arc::new(server_config)))
+}
+
+fn server_config(cert: &Path, key: &Path) -> anyhow::Result<ServerConfig> {
+    let chain = load_certs(cert)?;
+    let key = load_key(key)?;
+
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(chain, key)?;
+
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok(config)
+}
+
+/// Loads the full certificate chain, leaf certificate first
+fn load_certs(path: &Path) -> anyhow::Result<Vec<Certificate>> {
+    let data = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+
+    let chain = rustls_pemfile::certs(&mut &*data)?
+        .into_iter()
+        .map(Certificate)
+        .collect::<Vec<_>>();
+
+    if chain.is_empty() {
+        return Err(anyhow!("no certificates found in {}", path.display()));
+    }
+
+    Ok(chain)
+}
+
+/// Loads the first RSA (PKCS#1), PKCS#8 or EC (SEC1) private key
+fn load_key(path: &Path) -> anyhow::Result<PrivateKey> {
+    let data = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+
+    for item in rustls_pemfile::read_all(&mut &*data)? {
+        match item {
+            Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => {
+                return Ok(PrivateKey(key));
+            }
+
+            _ => (),
+        }
+    }
+
+    Err(anyhow!("no private key found in {}", path.display()))
+}